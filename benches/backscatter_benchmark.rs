@@ -1,16 +1,97 @@
-use backscatter_rs::fitting::fitacf3::fitacf_v3::{fit_rawacf_record, Fitacf3Error};
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{
+    fit_rawacf_record, fit_rawacf_record_in, FitOptions, FitWorkspace,
+};
 use backscatter_rs::utils::hdw::HdwInfo;
 use chrono::NaiveDateTime;
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
 
-use dmap;
 use rayon::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
 use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Wraps the system allocator to count the number of allocations made while fitting.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("Fitacf3", |b| b.iter(|| fitacf3()));
-    c.bench_function("Parallel Fitacf3", |b| b.iter(|| rayon_fitacf3()));
+    c.bench_function("Fitacf3", |b| b.iter(fitacf3));
+    c.bench_function("Parallel Fitacf3", |b| b.iter(rayon_fitacf3));
+
+    // Compare fitting with fresh allocations against fitting with a reused workspace
+    let file =
+        File::open("tests/test_files/20210607.1801.00.cly.a.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let hdw = read_hdw(&rawacf[0]);
+    let options = FitOptions::default();
+    let mut workspace = FitWorkspace::new();
+
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    for rec in rawacf.iter() {
+        fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+    }
+    let fresh = ALLOCATIONS.load(Ordering::Relaxed) - start;
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    for rec in rawacf.iter() {
+        fit_rawacf_record_in(rec, &hdw, &options, &mut workspace).expect("Could not fit record");
+    }
+    let reused = ALLOCATIONS.load(Ordering::Relaxed) - start;
+    println!(
+        "Allocations per record: {:.1} without workspace, {:.1} with workspace",
+        fresh as f64 / rawacf.len() as f64,
+        reused as f64 / rawacf.len() as f64
+    );
+
+    let mut group = c.benchmark_group("Fit records");
+    group.throughput(Throughput::Elements(rawacf.len() as u64));
+    group.bench_function("Without workspace", |b| {
+        b.iter(|| {
+            for rec in rawacf.iter() {
+                fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+            }
+        })
+    });
+    group.bench_function("With workspace", |b| {
+        b.iter(|| {
+            for rec in rawacf.iter() {
+                fit_rawacf_record_in(rec, &hdw, &options, &mut workspace)
+                    .expect("Could not fit record");
+            }
+        })
+    });
+    group.finish();
+}
+
+fn read_hdw(rec: &RawacfRecord) -> HdwInfo {
+    let file_datetime = NaiveDateTime::parse_from_str(
+        format!(
+            "{:4}{:0>2}{:0>2} {:0>2}:{:0>2}:{:0>2}",
+            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second
+        )
+        .as_str(),
+        "%Y%m%d %H:%M:%S",
+    )
+    .expect("Unable to interpret record timestamp");
+    HdwInfo::new(rec.station_id, file_datetime).expect("Unable to read hdw file")
 }
 
 fn fitacf3() {
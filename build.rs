@@ -1,15 +1,84 @@
-use git2::Repository;
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 fn main() {
-    // clone the utils repo
-    let out_dir = env::var("HDW_DIR").expect("HDW_DIR not set");
-    let url = "https://github.com/SuperDARN/hdw";
-    if !Path::new(&out_dir).is_dir() {
-        match Repository::clone(url, out_dir) {
-            Ok(r) => r,
-            Err(err) => panic!("failed to clone: {}", err),
-        };
+    let hdw_dir = hdw_source_dir();
+    println!("cargo:rerun-if-env-changed=HDW_DIR");
+    println!("cargo:rerun-if-changed={}", hdw_dir.display());
+    let has_hdw_files = fs::read_dir(&hdw_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .any(|e| e.file_name().to_string_lossy().starts_with("hdw.dat."))
+        })
+        .unwrap_or(false);
+    if !has_hdw_files {
+        // A directory chosen with HDW_DIR is expected to hold the files, so an empty one is a
+        // mistake. Without the vendored snapshot, hdw files can still be read at run time.
+        if env::var_os("HDW_DIR").is_some() {
+            panic!("No hdw.dat files found in HDW_DIR {}", hdw_dir.display());
+        }
+        println!(
+            "cargo:warning=No hdw.dat files found in {}, so none are embedded; run \
+             scripts/update_hdw.sh or set BACKSCATTER_HDW_DIR at run time",
+            hdw_dir.display()
+        );
     }
+    println!("cargo:rustc-env=HDW_EMBED_DIR={}", hdw_dir.display());
+    println!("cargo:rustc-env=HDW_VERSION={}", hdw_version(&hdw_dir));
+}
+
+/// Directory holding the hdw files to embed. By default this is the snapshot vendored in hdw/,
+/// which `HDW_DIR` overrides with a local path. With the `fetch-hdw` feature the files are
+/// instead cloned from the SuperDARN hdw repository.
+fn hdw_source_dir() -> PathBuf {
+    #[cfg(feature = "fetch-hdw")]
+    {
+        let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+        let clone_dir = Path::new(&out_dir).join("hdw");
+        if !clone_dir.is_dir() {
+            if let Err(err) =
+                git2::Repository::clone("https://github.com/SuperDARN/hdw", &clone_dir)
+            {
+                panic!("failed to clone: {}", err)
+            }
+        }
+        clone_dir
+    }
+    #[cfg(not(feature = "fetch-hdw"))]
+    {
+        let crate_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
+        let hdw_dir = env::var("HDW_DIR").unwrap_or_else(|_| "hdw".to_string());
+        Path::new(&crate_dir).join(hdw_dir)
+    }
+}
+
+/// Identifies the hdw snapshot, from its VERSION file or else the commit of a git checkout.
+fn hdw_version(hdw_dir: &Path) -> String {
+    if let Ok(version) = fs::read_to_string(hdw_dir.join("VERSION")) {
+        return version.trim().to_string();
+    }
+    git_commit(hdw_dir).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Commit checked out in a git repository, read from its HEAD and refs
+fn git_commit(repo_dir: &Path) -> Option<String> {
+    let git_dir = repo_dir.join(".git");
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    let Some(reference) = head.strip_prefix("ref: ") else {
+        return Some(head.to_string());
+    };
+    if let Ok(commit) = fs::read_to_string(git_dir.join(reference)) {
+        return Some(commit.trim().to_string());
+    }
+    // Refs which git has packed are listed as "<commit> <ref>"
+    fs::read_to_string(git_dir.join("packed-refs"))
+        .ok()?
+        .lines()
+        .find_map(|line| match line.split_once(' ') {
+            Some((commit, name)) if name == reference => Some(commit.to_string()),
+            _ => None,
+        })
 }
@@ -0,0 +1,60 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use backscatter_rs::fitting::acf_data::AcfRecord;
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{fit_acf_data, FitOptions};
+use backscatter_rs::fitting::fitacf3::fitstruct::FitType;
+use backscatter_rs::fitting::fitacf3::model::model_acfs;
+use backscatter_rs::utils::hdw_format::parse_lines;
+use libfuzzer_sys::fuzz_target;
+
+/// Fields of an `AcfRecord`, which has no `Arbitrary` implementation of its own
+#[derive(Arbitrary, Debug)]
+struct Input {
+    parameters: [i16; 11],
+    search_noise: f32,
+    pulse_table: Vec<i16>,
+    lag_table: Vec<i16>,
+    lag_zero_power: Vec<f32>,
+    range_list: Vec<i16>,
+    acfs: Vec<f32>,
+    xcfs: Option<Vec<f32>>,
+    clip_sigma: Option<f64>,
+    parallel_ranges: bool,
+}
+
+const HDW_LINE: &str = "66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n";
+
+fuzz_target!(|input: Input| {
+    let p = input.parameters;
+    let rec = AcfRecord {
+        num_averages: p[0],
+        lag_to_first_range: p[1],
+        sample_separation: p[2],
+        tx_pulse_length: p[3],
+        multi_pulse_increment: p[4],
+        offset: p[5],
+        channel: p[6],
+        beam_num: p[7],
+        tx_freq: p[8],
+        num_ranges: p[9],
+        num_lags: p[10],
+        search_noise: input.search_noise,
+        pulse_table: input.pulse_table,
+        lag_table: input.lag_table,
+        lag_zero_power: input.lag_zero_power,
+        range_list: input.range_list,
+        acfs: input.acfs,
+        xcfs: input.xcfs,
+    };
+    let hdw = parse_lines(HDW_LINE.as_bytes())
+        .expect("Unable to parse hdw line")
+        .remove(0)
+        .hdw;
+    let options = FitOptions {
+        clip_sigma: input.clip_sigma,
+        parallel_ranges: input.parallel_ranges,
+    };
+    let _ = fit_acf_data(&rec, &hdw, &options);
+    let _ = model_acfs(&rec, FitType::Quadratic, &options);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{fit_ranges, FitOptions};
+use backscatter_rs::utils::dmap_stream::RecordReader;
+use dmap::formats::RawacfRecord;
+use libfuzzer_sys::fuzz_target;
+
+// Fits each record which can be read, stopping at the first which cannot
+fuzz_target!(|data: &[u8]| {
+    for rec in RecordReader::<RawacfRecord, _>::new(data) {
+        match rec {
+            Ok(rec) => {
+                let _ = fit_ranges(&rec, &FitOptions::default());
+            }
+            Err(_) => break,
+        }
+    }
+});
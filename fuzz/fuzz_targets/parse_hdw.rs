@@ -0,0 +1,9 @@
+#![no_main]
+
+use backscatter_rs::utils::hdw_format::{lint, parse_lines};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_lines(data);
+    let _ = lint(data, Some(66));
+});
@@ -0,0 +1,158 @@
+#ifndef BACKSCATTER_RS_H
+#define BACKSCATTER_RS_H
+
+/* Generated by cbindgen from src/capi.rs with scripts/update_c_header.sh. Do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Result of a call into the library
+typedef enum BsStatus {
+  BS_STATUS_OK = 0,
+  // A required pointer argument was null
+  BS_STATUS_NULL_POINTER = 1,
+  // The input arrays or parameters are inconsistent
+  BS_STATUS_INVALID_INPUT = 2,
+  // No hardware parameters were found for the radar
+  BS_STATUS_HDW_NOT_FOUND = 3,
+  // The fitting routines were unable to fit the record
+  BS_STATUS_FIT_FAILED = 4,
+  // An internal error occurred
+  BS_STATUS_INTERNAL = 5,
+} BsStatus;
+
+// The ACF data of one integration period. All arrays are owned by the caller and are only
+// read during the call.
+typedef struct BsAcfInput {
+  int16_t num_averages;
+  int16_t num_pulses;
+  int16_t num_lags;
+  int16_t num_ranges;
+  // Lag to the first range, in microseconds
+  int16_t lag_to_first_range;
+  // Sample separation, in microseconds
+  int16_t sample_separation;
+  // Length of a transmitted pulse, in microseconds
+  int16_t tx_pulse_length;
+  // Basic lag time of the pulse sequence, in microseconds
+  int16_t multi_pulse_increment;
+  int16_t offset;
+  int16_t channel;
+  int16_t beam_num;
+  // Transmitted frequency, in kHz
+  int16_t tx_freq;
+  float search_noise;
+  // `num_pulses` pulse positions, in units of `multi_pulse_increment`
+  const int16_t *pulse_table;
+  // `num_lags` pairs of pulses, flattened row-major
+  const int16_t *lag_table;
+  // `num_ranges` lag zero powers
+  const float *lag_zero_power;
+  // Range gates with ACF data
+  const int16_t *range_list;
+  size_t range_list_len;
+  // `[range_list_len, num_lags, 2]` ACF values, flattened row-major
+  const float *acfs;
+  // `[range_list_len, num_lags, 2]` XCF values, or null if there are none
+  const float *xcfs;
+} BsAcfInput;
+
+// Hardware parameters of a radar, as listed in its hdw file
+typedef struct BsHdw {
+  int16_t station_id;
+  float latitude;
+  float longitude;
+  float altitude;
+  float boresight;
+  float boresight_shift;
+  float beam_separation;
+  float velocity_sign;
+  float phase_sign;
+  float tdiff_a;
+  float tdiff_b;
+  float intf_offset_x;
+  float intf_offset_y;
+  float intf_offset_z;
+  float rx_rise_time;
+  float rx_atten_step;
+  float attenuation_stages;
+  int16_t max_num_ranges;
+  int16_t max_num_beams;
+} BsHdw;
+
+// Fitted parameters of a single range gate, in the units of the fitacf format. Fields prefixed
+// with `lambda` come from the exponential (linear) power fit, and those prefixed with `sigma`
+// from the Gaussian (quadratic) power fit.
+typedef struct BsRangeFit {
+  int16_t range_num;
+  int16_t fitted_points;
+  // Lags rejected from the power and phase fits as outliers, when clipping is enabled
+  int16_t clipped_points;
+  int8_t quality_flag;
+  int8_t ground_flag;
+  // Power relative to the noise, in dB
+  float lambda_power;
+  float lambda_power_error;
+  float sigma_power;
+  float sigma_power_error;
+  // Line-of-sight velocity, in m/s
+  float velocity;
+  float velocity_error;
+  // Spectral width, in m/s
+  float lambda_spectral_width;
+  float lambda_spectral_width_error;
+  float sigma_spectral_width;
+  float sigma_spectral_width_error;
+  float lambda_std_dev;
+  float sigma_std_dev;
+  float phi_std_dev;
+  // Lag zero phase of the XCF, in radians
+  float xcf_phi0;
+  float xcf_phi0_error;
+  float xcf_phi_std_dev;
+  // Elevation angle, in degrees
+  float elevation;
+  float elevation_low;
+  float elevation_high;
+} BsRangeFit;
+
+// Fitted parameters of a record. Owned by the library; release with `bs_fit_result_free`.
+typedef struct BsFitResult {
+  // Noise power used as the ACF cutoff
+  float sky_noise;
+  // `num_ranges` of the input lag zero powers relative to the noise, in dB
+  float *lag_zero_power_db;
+  size_t lag_zero_power_len;
+  // Fitted parameters of each range which survived filtering
+  struct BsRangeFit *ranges;
+  size_t ranges_len;
+} BsFitResult;
+
+// Fits one record with the FITACF 3.0 algorithm. On success, `*out` points to a result which
+// the caller must release with `bs_fit_result_free`; otherwise `*out` is set to null.
+//
+// # Safety
+// `input` and `hdw` must point to valid structs whose arrays hold the number of elements
+// documented on `BsAcfInput`, and `out` must be a valid pointer.
+enum BsStatus bs_fit_acf(const struct BsAcfInput *input,
+                         const struct BsHdw *hdw,
+                         struct BsFitResult **out);
+
+// Releases a result returned by `bs_fit_acf`. Passing null does nothing.
+//
+// # Safety
+// `result` must be null or a pointer returned by `bs_fit_acf` which has not yet been freed.
+void bs_fit_result_free(struct BsFitResult *result);
+
+// Looks up the hardware parameters of a radar from the bundled hdw files, writing them to
+// `*out`. `time` is in seconds since the Unix epoch, UTC.
+//
+// # Safety
+// `out` must be a valid pointer.
+enum BsStatus bs_hdw_lookup(int16_t station_id, int64_t time, struct BsHdw *out);
+
+// Describes the most recent failure on the calling thread. The string is owned by the
+// library and is valid until the next call into it from the same thread.
+const char *bs_last_error(void);
+
+#endif /* BACKSCATTER_RS_H */
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Regenerates the C header of the C API in src/capi.rs. Needs cbindgen, which can be installed
+# with `cargo install cbindgen`.
+# Usage: scripts/update_c_header.sh
+set -e
+
+crate_dir=$(cd "$(dirname "$0")/.." && pwd)
+cbindgen --config "$crate_dir/cbindgen.toml" --output "$crate_dir/include/backscatter_rs.h" "$crate_dir"
+echo "Updated include/backscatter_rs.h"
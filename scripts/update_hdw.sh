@@ -0,0 +1,17 @@
+#!/bin/sh
+# Replaces the vendored hdw files with those from a revision of the SuperDARN hdw repository.
+# Usage: scripts/update_hdw.sh [revision]
+set -e
+
+revision=${1:-HEAD}
+hdw_dir=$(cd "$(dirname "$0")/../hdw" && pwd)
+checkout=$(mktemp -d)
+trap 'rm -rf "$checkout"' EXIT
+
+git clone --quiet https://github.com/SuperDARN/hdw "$checkout"
+git -C "$checkout" checkout --quiet "$revision"
+
+rm -f "$hdw_dir"/hdw.dat.*
+cp "$checkout"/hdw.dat.* "$hdw_dir"/
+echo "$(git -C "$checkout" rev-parse HEAD) $(git -C "$checkout" log -1 --format=%cs)" > "$hdw_dir/VERSION"
+echo "Updated hdw files to $(cat "$hdw_dir/VERSION")"
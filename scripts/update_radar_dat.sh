@@ -0,0 +1,16 @@
+#!/bin/sh
+# Replaces the bundled station registry with radar.dat from a revision of the SuperDARN RST
+# repository.
+# Usage: scripts/update_radar_dat.sh [revision]
+set -e
+
+revision=${1:-HEAD}
+data_dir=$(cd "$(dirname "$0")/../data" && pwd)
+checkout=$(mktemp -d)
+trap 'rm -rf "$checkout"' EXIT
+
+git clone --quiet https://github.com/SuperDARN/rst "$checkout"
+git -C "$checkout" checkout --quiet "$revision"
+
+cp "$checkout"/tables/superdarn/radar.dat "$data_dir"/radar.dat
+echo "Updated radar.dat to $(git -C "$checkout" rev-parse HEAD)"
@@ -0,0 +1,312 @@
+use crate::records::{is_stdio, STDIO};
+use crate::BinResult;
+use backscatter_rs::utils::compression::Compression;
+use clap::{Args, ValueEnum};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+/// Name of the file in the output directory recording the checksum of the input of each output
+const MANIFEST_NAME: &str = ".procdarn-manifest";
+
+// Input files of a subcommand which processes many files
+#[derive(Args, Debug)]
+pub struct InputFilesArgs {
+    /// Input files, directories searched recursively for input files, quoted glob patterns, or -
+    /// for stdin
+    #[arg(short, long = "infile", required = true, num_args = 1..)]
+    pub inputs: Vec<String>,
+}
+
+impl InputFilesArgs {
+    /// Expands the inputs into files, each with the path of its output relative to an output
+    /// directory. Files found in directories are those whose name contains `input_type`.
+    pub fn files(&self, input_type: &str) -> BinResult<Vec<(PathBuf, PathBuf)>> {
+        let mut inputs: Vec<(PathBuf, PathBuf)> = vec![];
+        for pattern in self.inputs.iter() {
+            let path = Path::new(pattern);
+            if is_stdio(path) {
+                inputs.push((path.to_path_buf(), PathBuf::from(STDIO)));
+            } else if path.is_dir() {
+                for entry in WalkDir::new(path).sort_by_file_name() {
+                    let entry = entry?;
+                    let is_input = entry
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|name| name.contains(input_type));
+                    if entry.file_type().is_file() && is_input {
+                        let relative = entry.path().strip_prefix(path)?.to_path_buf();
+                        inputs.push((entry.path().to_path_buf(), relative));
+                    }
+                }
+            } else if path.exists() {
+                inputs.push((path.to_path_buf(), file_name(path)?));
+            } else {
+                let mut matched = false;
+                for entry in glob::glob(pattern)? {
+                    let entry = entry?;
+                    if entry.is_file() {
+                        let relative = file_name(&entry)?;
+                        inputs.push((entry, relative));
+                        matched = true;
+                    }
+                }
+                if !matched {
+                    Err(format!("No input files found at {}", pattern))?
+                }
+            }
+        }
+        Ok(inputs)
+    }
+}
+
+// Input files of a subcommand which processes many files, and where to write their outputs
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    #[command(flatten)]
+    pub inputs: InputFilesArgs,
+
+    /// Output file, or - for stdout, for a single input
+    #[arg(
+        short,
+        long,
+        required_unless_present = "outdir",
+        conflicts_with = "outdir"
+    )]
+    pub outfile: Option<PathBuf>,
+
+    /// Output directory. Outputs are named after their inputs, with the input type in the file
+    /// name replaced by the output type, e.g. 20210607.1801.00.cly.a.rawacf becomes
+    /// 20210607.1801.00.cly.a.fitacf. Inputs found under a directory keep their subdirectory
+    #[arg(long)]
+    pub outdir: Option<PathBuf>,
+
+    /// Write every output directly into the output directory, without subdirectories
+    #[arg(long, requires = "outdir")]
+    pub flatten: bool,
+
+    /// Compression of the outputs: none, bz2, gz or zst. Outputs in the output directory get the
+    /// extension of the compression [default: from the output extension]
+    #[arg(long)]
+    pub compress: Option<Compression>,
+
+    /// Skip inputs whose output is already complete, to resume an interrupted batch
+    #[arg(long, value_enum)]
+    pub resume: Option<Resume>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    /// Skip inputs whose output is newer than the input
+    Timestamp,
+    /// Skip inputs whose checksum matches the one recorded when their output was written
+    Checksum,
+}
+
+/// An input file and the output it is processed into
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+impl BatchArgs {
+    /// Expands the inputs into jobs. Files found in directories are those whose name contains
+    /// `input_type`, which is replaced by `output_type` to name the outputs.
+    pub fn jobs(&self, input_type: &str, output_type: &str) -> BinResult<Vec<Job>> {
+        // Each input file, with the path of its output relative to the output directory
+        let inputs = self.inputs.files(input_type)?;
+
+        let jobs: Vec<Job> = match (&self.outfile, &self.outdir) {
+            (Some(outfile), _) => match &inputs[..] {
+                [(input, _)] => vec![Job {
+                    input: input.clone(),
+                    output: outfile.clone(),
+                }],
+                _ => Err("--outfile needs a single input file; use --outdir for many")?,
+            },
+            (None, Some(_)) if inputs.iter().any(|(input, _)| is_stdio(input)) => {
+                Err("Reading from stdin needs --outfile")?
+            }
+            (None, Some(outdir)) => inputs
+                .into_iter()
+                .map(|(input, relative)| {
+                    let relative = match self.flatten {
+                        true => PathBuf::from(relative.file_name().unwrap_or_default()),
+                        false => relative,
+                    };
+                    let output = outdir.join(output_name(&relative, input_type, output_type));
+                    let output = match self.compress {
+                        Some(compression) => with_compression(&output, compression),
+                        None => output,
+                    };
+                    Job { input, output }
+                })
+                .collect(),
+            (None, None) => Err("Either --outfile or --outdir is needed")?,
+        };
+
+        let mut outputs: Vec<&PathBuf> = jobs.iter().map(|job| &job.output).collect();
+        outputs.sort();
+        if let Some(pair) = outputs.windows(2).find(|pair| pair[0] == pair[1]) {
+            Err(format!(
+                "More than one input would be written to {}",
+                pair[0].display()
+            ))?
+        }
+        Ok(jobs)
+    }
+
+    /// Compression of an output
+    pub fn compression(&self, output: &Path) -> Compression {
+        self.compress
+            .unwrap_or_else(|| Compression::from_extension(output))
+    }
+
+    /// Directory holding the outputs and the resume manifest
+    pub fn output_root(&self) -> PathBuf {
+        match (&self.outdir, &self.outfile) {
+            (Some(outdir), _) => outdir.clone(),
+            (None, Some(outfile)) => outfile.parent().map(Path::to_path_buf).unwrap_or_default(),
+            (None, None) => PathBuf::new(),
+        }
+    }
+}
+
+fn file_name(path: &Path) -> BinResult<PathBuf> {
+    Ok(PathBuf::from(path.file_name().ok_or_else(|| {
+        format!("{} is not a file", path.display())
+    })?))
+}
+
+/// Names an output after its input, replacing `input_type` in the file name by `output_type`,
+/// or appending `output_type` as an extension if the input type is not in the name.
+fn output_name(input: &Path, input_type: &str, output_type: &str) -> PathBuf {
+    let name = input
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = match name.contains(input_type) {
+        true => name.replace(input_type, output_type),
+        false => format!("{}.{}", name, output_type),
+    };
+    input.with_file_name(name)
+}
+
+/// Replaces the compression extension of a file name, if any, by that of `compression`
+fn with_compression(path: &Path, compression: Compression) -> PathBuf {
+    let path = match Compression::from_extension(path) {
+        Compression::None => path.to_path_buf(),
+        _ => path.with_extension(""),
+    };
+    match compression.extension() {
+        Some(extension) => {
+            let mut name = path.into_os_string();
+            name.push(".");
+            name.push(extension);
+            PathBuf::from(name)
+        }
+        None => path,
+    }
+}
+
+/// Decides which jobs are already complete, and records the jobs completed by this run.
+pub struct ResumeState {
+    mode: Option<Resume>,
+    root: PathBuf,
+    /// Checksum of the input of each output, by the output's path relative to `root`
+    checksums: HashMap<String, String>,
+    manifest: Option<Mutex<File>>,
+}
+
+impl ResumeState {
+    pub fn new(mode: Option<Resume>, root: PathBuf) -> BinResult<ResumeState> {
+        let mut checksums = HashMap::new();
+        let mut manifest = None;
+        if mode == Some(Resume::Checksum) {
+            let path = root.join(MANIFEST_NAME);
+            if path.exists() {
+                for line in BufReader::new(File::open(&path)?).lines() {
+                    if let Some((checksum, output)) = line?.split_once('\t') {
+                        checksums.insert(output.to_string(), checksum.to_string());
+                    }
+                }
+            }
+            std::fs::create_dir_all(&root)?;
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            manifest = Some(Mutex::new(file));
+        }
+        Ok(ResumeState {
+            mode,
+            root,
+            checksums,
+            manifest,
+        })
+    }
+
+    /// Whether the output of a job was completed by an earlier run. Outputs are only created
+    /// once complete, so an existing output is never partial. Jobs reading stdin or writing
+    /// stdout are never complete.
+    pub fn is_complete(&self, job: &Job) -> BinResult<bool> {
+        if is_stdio(&job.input) || is_stdio(&job.output) || !job.output.exists() {
+            return Ok(false);
+        }
+        match self.mode {
+            None => Ok(false),
+            Some(Resume::Timestamp) => Ok(std::fs::metadata(&job.output)?.modified()?
+                >= std::fs::metadata(&job.input)?.modified()?),
+            Some(Resume::Checksum) => match self.checksums.get(&self.manifest_key(&job.output)) {
+                Some(checksum) => Ok(*checksum == sha256(&job.input)?),
+                None => Ok(false),
+            },
+        }
+    }
+
+    /// Records that the output of a job is complete.
+    pub fn complete(&self, job: &Job) -> BinResult<()> {
+        if is_stdio(&job.input) || is_stdio(&job.output) {
+            return Ok(());
+        }
+        if let Some(manifest) = &self.manifest {
+            let line = format!(
+                "{}\t{}\n",
+                sha256(&job.input)?,
+                self.manifest_key(&job.output)
+            );
+            let mut file = manifest.lock().map_err(|_| "Resume manifest is poisoned")?;
+            file.write_all(line.as_bytes())?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn manifest_key(&self, output: &Path) -> String {
+        output
+            .strip_prefix(&self.root)
+            .unwrap_or(output)
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+/// Hex-encoded SHA-256 of a file
+fn sha256(path: &Path) -> BinResult<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Path an output is written to before being renamed into place once complete
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
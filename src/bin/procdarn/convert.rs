@@ -0,0 +1,96 @@
+use crate::records::{create_output, RecordFile};
+use crate::{BinResult, InputArgs, OutputArgs};
+use backscatter_rs::utils::dmap_stream::RecordWriter;
+use clap::{Args, ValueEnum};
+use dmap::formats::{DmapRecord, FitacfRecord};
+use dmap::{DmapVec, InDmap};
+use std::io::Write;
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Format of the output file
+    #[arg(long, value_enum, default_value_t = OutputFormat::Dmap)]
+    to: OutputFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    /// DMAP records of the same type as the input
+    Dmap,
+    /// Comma-separated values with one row per fitted range. Fitacf files only
+    Csv,
+}
+
+/// Columns of the CSV output, one row per range in `range_list`
+const CSV_HEADER: &str = "time,station_id,beam,channel,range,fitted_points,quality_flag,\
+ground_flag,lambda_power,lambda_power_error,velocity,velocity_error,lambda_spectral_width,\
+lambda_spectral_width_error,elevation";
+
+pub fn run(args: &ConvertArgs) -> BinResult<()> {
+    let file = RecordFile::read(&args.input.infile)?;
+    let mut writer = create_output(&args.output.outfile, args.output.compression())?;
+    match (args.to, file) {
+        (OutputFormat::Dmap, RecordFile::Rawacf(records)) => write_dmap(&records, &mut writer)?,
+        (OutputFormat::Dmap, RecordFile::Fitacf(records)) => write_dmap(&records, &mut writer)?,
+        (OutputFormat::Csv, RecordFile::Fitacf(records)) => write_csv(&records, &mut writer)?,
+        (OutputFormat::Csv, RecordFile::Rawacf(_)) => {
+            Err("CSV output is only supported for fitacf files")?
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_dmap(records: &[impl DmapRecord], writer: impl Write) -> BinResult<()> {
+    let mut writer = RecordWriter::new(writer);
+    for rec in records {
+        writer.write(rec)?;
+    }
+    Ok(())
+}
+
+fn write_csv(records: &[FitacfRecord], mut writer: impl Write) -> BinResult<()> {
+    writeln!(writer, "{}", CSV_HEADER)?;
+    for rec in records {
+        let time = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second, rec.microsecond
+        );
+        for (i, range) in rec.range_list.data.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                time,
+                rec.station_id,
+                rec.beam_num,
+                rec.channel,
+                range,
+                rec.fitted_points.data[i],
+                rec.quality_flag.data[i],
+                rec.ground_flag.data[i],
+                rec.lambda_power.data[i],
+                rec.lambda_power_error.data[i],
+                rec.velocity.data[i],
+                rec.velocity_error.data[i],
+                rec.lambda_spectral_width.data[i],
+                rec.lambda_spectral_width_error.data[i],
+                optional_value(&rec.elevation, i),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Value of an optional per-range field, or an empty string if the record lacks it
+fn optional_value<T: InDmap + ToString>(field: &Option<DmapVec<T>>, i: usize) -> String {
+    field
+        .as_ref()
+        .and_then(|x| x.data.get(i))
+        .map_or(String::new(), ToString::to_string)
+}
@@ -0,0 +1,146 @@
+use crate::records::{RecordFile, RecordHeader};
+use crate::BinResult;
+use clap::Args;
+use dmap::formats::FitacfRecord;
+use dmap::{DmapVec, InDmap};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Reference fitacf file
+    old: PathBuf,
+
+    /// Fitacf file compared against the reference
+    new: PathBuf,
+
+    /// Absolute tolerance of fitted values
+    #[arg(long, default_value_t = 1e-6)]
+    atol: f64,
+
+    /// Tolerance of fitted values relative to the reference
+    #[arg(long, default_value_t = 1e-5)]
+    rtol: f64,
+}
+
+/// Compares the records of two fitacf files pairwise. Returns whether the files match.
+pub fn run(args: &DiffArgs) -> BinResult<bool> {
+    let old = read_fitacf(&args.old)?;
+    let new = read_fitacf(&args.new)?;
+
+    let mut differing = 0;
+    if old.len() != new.len() {
+        println!("record count: {} != {}", old.len(), new.len());
+    }
+    for (rec_num, (a, b)) in old.iter().zip(new.iter()).enumerate() {
+        let mut diffs = vec![];
+        let (header_a, header_b) = (RecordHeader::from(a), RecordHeader::from(b));
+        if header_a != header_b {
+            diffs.push(format!("header: {:?} != {:?}", header_a, header_b));
+        }
+        if a.range_list != b.range_list {
+            diffs.push("range_list differs".to_string());
+        } else {
+            for ((name, vals_a), (_, vals_b)) in range_fields(a).into_iter().zip(range_fields(b)) {
+                match (vals_a, vals_b) {
+                    (Some(x), Some(y)) => {
+                        if let Some(diff) = compare(&x, &y, args.atol, args.rtol) {
+                            diffs.push(format!("{}: {}", name, diff));
+                        }
+                    }
+                    (None, None) => {}
+                    (Some(_), None) => diffs.push(format!("{}: missing from new", name)),
+                    (None, Some(_)) => diffs.push(format!("{}: missing from old", name)),
+                }
+            }
+        }
+        if !diffs.is_empty() {
+            differing += 1;
+            for diff in diffs {
+                println!("record {}: {}", rec_num, diff);
+            }
+        }
+    }
+    let compared = old.len().min(new.len());
+    println!("{} of {} records differ", differing, compared);
+    Ok(differing == 0 && old.len() == new.len())
+}
+
+fn read_fitacf(path: &Path) -> BinResult<Vec<FitacfRecord>> {
+    match RecordFile::read(path)? {
+        RecordFile::Fitacf(records) => Ok(records),
+        RecordFile::Rawacf(_) => Err(format!("{} is not a fitacf file", path.display()))?,
+    }
+}
+
+/// The per-range fields of a record, as f64. Optional fields absent from the record are `None`.
+fn range_fields(rec: &FitacfRecord) -> Vec<(&'static str, Option<Vec<f64>>)> {
+    fn values<T: InDmap + Copy + Into<f64>>(field: &DmapVec<T>) -> Vec<f64> {
+        field.data.iter().map(|&x| x.into()).collect()
+    }
+    fn optional<T: InDmap + Copy + Into<f64>>(field: &Option<DmapVec<T>>) -> Option<Vec<f64>> {
+        field.as_ref().map(values)
+    }
+    vec![
+        ("fitted_points", Some(values(&rec.fitted_points))),
+        ("quality_flag", Some(values(&rec.quality_flag))),
+        ("ground_flag", Some(values(&rec.ground_flag))),
+        ("lambda_power", Some(values(&rec.lambda_power))),
+        ("lambda_power_error", Some(values(&rec.lambda_power_error))),
+        ("sigma_power", Some(values(&rec.sigma_power))),
+        ("sigma_power_error", Some(values(&rec.sigma_power_error))),
+        ("velocity", Some(values(&rec.velocity))),
+        ("velocity_error", Some(values(&rec.velocity_error))),
+        (
+            "lambda_spectral_width",
+            Some(values(&rec.lambda_spectral_width)),
+        ),
+        (
+            "lambda_spectral_width_error",
+            Some(values(&rec.lambda_spectral_width_error)),
+        ),
+        (
+            "sigma_spectral_width",
+            Some(values(&rec.sigma_spectral_width)),
+        ),
+        (
+            "sigma_spectral_width_error",
+            Some(values(&rec.sigma_spectral_width_error)),
+        ),
+        ("lambda_std_dev", Some(values(&rec.lambda_std_dev))),
+        ("sigma_std_dev", Some(values(&rec.sigma_std_dev))),
+        ("phi_std_dev", Some(values(&rec.phi_std_dev))),
+        ("xcf_velocity", optional(&rec.xcf_velocity)),
+        ("lag_zero_phi", optional(&rec.lag_zero_phi)),
+        ("elevation", optional(&rec.elevation)),
+        ("elevation_error", optional(&rec.elevation_error)),
+    ]
+}
+
+/// Describes how two fields differ, or returns `None` if every value is within tolerance.
+/// NaN matches NaN.
+fn compare(old: &[f64], new: &[f64], atol: f64, rtol: f64) -> Option<String> {
+    if old.len() != new.len() {
+        return Some(format!("length {} != {}", old.len(), new.len()));
+    }
+    let mut count = 0;
+    let mut max_diff: f64 = 0.0;
+    for (&a, &b) in old.iter().zip(new.iter()) {
+        if a == b || (a.is_nan() && b.is_nan()) {
+            continue;
+        }
+        let diff = (a - b).abs();
+        if diff.is_nan() || diff > atol + rtol * a.abs() {
+            count += 1;
+            max_diff = max_diff.max(diff);
+        }
+    }
+    match count {
+        0 => None,
+        _ => Some(format!(
+            "{} of {} ranges differ, by up to {:e}",
+            count,
+            old.len(),
+            max_diff
+        )),
+    }
+}
@@ -0,0 +1,388 @@
+use crate::batch::{partial_path, BatchArgs, Job, ResumeState};
+use crate::records::{create_output, is_stdio, open_input};
+use crate::{BinResult, HdwSourceArgs, ParallelArgs, Parallelism};
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{create_lag_list, fit_ranges, FitOptions};
+#[cfg(feature = "serde")]
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{trace_fit_stages, FitTrace};
+use backscatter_rs::fitting::fitacf3::fitstruct::FitType;
+use backscatter_rs::fitting::fitacf3::model::model_acfs;
+use backscatter_rs::fitting::fitacf3::stream::{fit_stream, StreamSummary};
+use backscatter_rs::utils::compression::Compression;
+use backscatter_rs::utils::dmap_stream::RecordReader;
+use backscatter_rs::utils::hdw::HdwProvider;
+#[cfg(feature = "serde")]
+use backscatter_rs::utils::hdw::{record_datetime, HdwInfo};
+use clap::{Args, ValueEnum};
+use dmap::formats::RawacfRecord;
+use rayon::prelude::*;
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::{BufWriter, Read, Write};
+use std::iter::zip;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, info_span, warn};
+
+type RawacfRecords = RecordReader<RawacfRecord, Box<dyn Read>>;
+
+#[derive(Args, Debug)]
+pub struct FitArgs {
+    #[command(flatten)]
+    batch: BatchArgs,
+
+    /// Reject lags with fit residuals beyond this many standard deviations, refitting until
+    /// no more lags are rejected
+    #[arg(long)]
+    clip_sigma: Option<f64>,
+
+    /// Fail on the first record which cannot be fitted, rather than skipping it with a warning
+    #[arg(long)]
+    strict: bool,
+
+    /// Write the measured ACF, model ACF and residuals to this file
+    #[arg(long)]
+    acf_dump: Option<PathBuf>,
+
+    /// Write the reason each rejected lag was removed from the power and phase fits to this file
+    #[arg(long)]
+    lag_mask_dump: Option<PathBuf>,
+
+    /// Write the ranges after every stage of the fitting pipeline to this file as JSON
+    #[cfg(feature = "serde")]
+    #[arg(long)]
+    stage_dump: Option<PathBuf>,
+
+    /// Comma-separated record numbers to include in the dumps [default: all]
+    #[arg(long, value_delimiter = ',')]
+    dump_records: Vec<usize>,
+
+    /// Comma-separated range gates to include in the dumps [default: all]
+    #[arg(long, value_delimiter = ',')]
+    dump_ranges: Vec<usize>,
+
+    /// Power fit used to rebuild the model ACF
+    #[arg(long, value_enum, default_value_t = PowerModel::Lambda)]
+    dump_model: PowerModel,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PowerModel {
+    /// Exponential decay, from the linear fit of log power
+    Lambda,
+    /// Gaussian decay, from the quadratic fit of log power
+    Sigma,
+}
+
+/// Fits rawacf files and writes the fitacf files, along with any requested dumps.
+pub fn run(args: &FitArgs, hdw: &HdwSourceArgs, parallel: &ParallelArgs) -> BinResult<()> {
+    let options = FitOptions {
+        clip_sigma: args.clip_sigma,
+        parallel_ranges: parallel.parallelism == Parallelism::Ranges,
+    };
+    let jobs = args.batch.jobs("rawacf", "fitacf")?;
+    #[cfg(feature = "serde")]
+    let stage_dump = args.stage_dump.is_some();
+    #[cfg(not(feature = "serde"))]
+    let stage_dump = false;
+    if args.acf_dump.is_some() || args.lag_mask_dump.is_some() || stage_dump {
+        match &jobs[..] {
+            [job] if is_stdio(&job.input) => Err("Dumps need an input file rather than stdin")?,
+            [_] => {}
+            _ => Err("Dumps need a single input file")?,
+        }
+    }
+    let resume = ResumeState::new(args.batch.resume, args.batch.output_root())?;
+
+    // Fit the files!
+    let fit_job = |hdw_provider: &mut HdwProvider, job: &Job| -> BinResult<()> {
+        if resume.is_complete(job)? {
+            info!(input = %job.input.display(), "Skipping input, since its output is complete");
+            return Ok(());
+        }
+        let _span = info_span!("fit_file", input = %job.input.display()).entered();
+        let partial = partial_path(&job.output);
+        let result = fit_file(
+            job,
+            &partial,
+            args.batch.compression(&job.output),
+            hdw_provider,
+            &options,
+            parallel.window(&job.output),
+            args.strict,
+        );
+        match &result {
+            Ok(summary) => {
+                if !summary.skipped.is_empty() {
+                    warn!(
+                        records = ?summary.skipped,
+                        "Skipped {} records which could not be fitted",
+                        summary.skipped.len()
+                    );
+                }
+                info!(
+                    records = summary.written,
+                    skipped = summary.skipped.len(),
+                    "Fitted file"
+                )
+            }
+            Err(_) => {
+                let _ = remove_file(&partial);
+            }
+        }
+        result?;
+        resume.complete(job)
+    };
+    let results: Vec<BinResult<()>> = match parallel.parallelism {
+        Parallelism::Files => jobs
+            .par_iter()
+            .map_init(|| HdwProvider::new(hdw.source()), fit_job)
+            .collect(),
+        Parallelism::Records | Parallelism::Ranges => {
+            let mut hdw_provider = HdwProvider::new(hdw.source());
+            jobs.iter()
+                .map(|job| fit_job(&mut hdw_provider, job))
+                .collect()
+        }
+    };
+    let mut errors: Vec<_> = zip(jobs.iter(), results)
+        .filter_map(|(job, result)| result.err().map(|e| (job, e)))
+        .collect();
+    if jobs.len() == 1 {
+        if let Some((_, e)) = errors.pop() {
+            return Err(e);
+        }
+    }
+    for (job, e) in errors.iter() {
+        error!(input = %job.input.display(), "{}", e);
+    }
+    if !errors.is_empty() {
+        Err(format!("{} of {} files failed", errors.len(), jobs.len()))?
+    }
+
+    let infile = &jobs[0].input;
+    if let Some(path) = &args.acf_dump {
+        dump_model_acfs(read_rawacf(infile)?, path, args, &options)?;
+    }
+    if let Some(path) = &args.lag_mask_dump {
+        dump_lag_masks(read_rawacf(infile)?, path, args, &options)?;
+    }
+    #[cfg(feature = "serde")]
+    if let Some(path) = &args.stage_dump {
+        let mut hdw_provider = HdwProvider::new(hdw.source());
+        dump_fit_stages(
+            read_rawacf(infile)?,
+            &mut hdw_provider,
+            path,
+            args,
+            &options,
+        )?;
+    }
+    Ok(())
+}
+
+/// Fits the records of one file into `partial`, then moves it to the output path once complete.
+/// Records are written straight to stdout if the output is `-`. Returns the number of records
+/// written and skipped.
+fn fit_file(
+    job: &Job,
+    partial: &Path,
+    compression: Compression,
+    hdw_provider: &mut HdwProvider,
+    options: &FitOptions,
+    window: usize,
+    strict: bool,
+) -> BinResult<StreamSummary> {
+    let rawacf = open_input(&job.input)?;
+    if is_stdio(&job.output) {
+        let mut fitacf = create_output(&job.output, compression)?;
+        let summary = fit_stream(rawacf, &mut fitacf, hdw_provider, options, window, strict)?;
+        fitacf.finish()?;
+        return Ok(summary);
+    }
+    if let Some(parent) = job.output.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut fitacf = create_output(partial, compression)?;
+    let summary = fit_stream(rawacf, &mut fitacf, hdw_provider, options, window, strict)?;
+    fitacf.finish()?;
+    rename(partial, &job.output)?;
+    Ok(summary)
+}
+
+/// Reads the records of a rawacf file one at a time
+fn read_rawacf(path: &Path) -> BinResult<RawacfRecords> {
+    Ok(RecordReader::new(open_input(path)?))
+}
+
+/// Passes on the fit of a record for a dump, or returns `None` for a record which cannot be
+/// fitted, so that it is skipped with a warning as `fit_stream` does, unless `strict` is set.
+fn fit_for_dump<T>(
+    fit: Result<T, impl std::fmt::Display>,
+    rec_num: usize,
+    strict: bool,
+) -> BinResult<Option<T>> {
+    match fit {
+        Ok(fit) => Ok(Some(fit)),
+        Err(e) if strict => Err(format!("Unable to fit record {}: {}", rec_num, e))?,
+        Err(e) => {
+            warn!(
+                record = rec_num,
+                "Skipping record which cannot be fitted: {}", e
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Writes the measured ACF, model ACF and residuals of the selected records and ranges
+/// as whitespace-separated columns, one line per lag. Records which cannot be fitted are left
+/// out.
+fn dump_model_acfs(
+    records: RawacfRecords,
+    path: &Path,
+    args: &FitArgs,
+    options: &FitOptions,
+) -> BinResult<()> {
+    let fit_type = match args.dump_model {
+        PowerModel::Lambda => FitType::Linear,
+        PowerModel::Sigma => FitType::Quadratic,
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(
+        writer,
+        "# record range lag t measured_re measured_im model_re model_im residual_re residual_im"
+    )?;
+    for (rec_num, rec) in records.enumerate() {
+        let rec = rec?;
+        if !args.dump_records.is_empty() && !args.dump_records.contains(&rec_num) {
+            continue;
+        }
+        let Some(acfs) = fit_for_dump(model_acfs(&rec, fit_type, options), rec_num, args.strict)?
+        else {
+            continue;
+        };
+        for acf in acfs {
+            if !args.dump_ranges.is_empty() && !args.dump_ranges.contains(&acf.range_num) {
+                continue;
+            }
+            for i in 0..acf.t.len() {
+                writeln!(
+                    writer,
+                    "{} {} {} {:e} {:e} {:e} {:e} {:e} {:e} {:e}",
+                    rec_num,
+                    acf.range_num,
+                    acf.lag_nums[i],
+                    acf.t[i],
+                    acf.measured[i][0],
+                    acf.measured[i][1],
+                    acf.model[i][0],
+                    acf.model[i][1],
+                    acf.residuals[i][0],
+                    acf.residuals[i][1]
+                )?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the rejection reason of every lag in the selected records and ranges, one line per
+/// lag. Lags kept in a fit are marked `ok`, and records which cannot be fitted are left out.
+fn dump_lag_masks(
+    records: RawacfRecords,
+    path: &Path,
+    args: &FitArgs,
+    options: &FitOptions,
+) -> BinResult<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "# record range lag power phase")?;
+    for (rec_num, rec) in records.enumerate() {
+        let rec = rec?;
+        if !args.dump_records.is_empty() && !args.dump_records.contains(&rec_num) {
+            continue;
+        }
+        let fit = create_lag_list(&rec)
+            .and_then(|lags| fit_ranges(&rec, options).map(|(ranges, _)| (lags, ranges)));
+        let Some((lags, ranges)) = fit_for_dump(fit, rec_num, args.strict)? else {
+            continue;
+        };
+        for range in ranges {
+            if !args.dump_ranges.is_empty() && !args.dump_ranges.contains(&range.range_num) {
+                continue;
+            }
+            for (i, lag) in lags.iter().enumerate() {
+                let power = range.power_mask[i].map_or("ok".to_string(), |r| r.to_string());
+                let phase = range.phase_mask[i].map_or("ok".to_string(), |r| r.to_string());
+                writeln!(
+                    writer,
+                    "{} {} {} {} {}",
+                    rec_num, range.range_num, lag.lag_num, power, phase
+                )?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the hardware parameters and the pipeline state after every fitting stage of the
+/// selected records and ranges as a JSON document. Records which cannot be fitted are left out.
+#[cfg(feature = "serde")]
+fn dump_fit_stages(
+    records: RawacfRecords,
+    hdw_provider: &mut HdwProvider,
+    path: &Path,
+    args: &FitArgs,
+    options: &FitOptions,
+) -> BinResult<()> {
+    #[derive(serde::Serialize)]
+    struct RecordTrace {
+        record: usize,
+        hdw: HdwInfo,
+        #[serde(flatten)]
+        trace: FitTrace,
+    }
+    #[derive(serde::Serialize)]
+    struct StageDump<'a> {
+        options: &'a FitOptions,
+        records: Vec<RecordTrace>,
+    }
+
+    let mut traces = vec![];
+    for (rec_num, rec) in records.enumerate() {
+        let rec = rec?;
+        if !args.dump_records.is_empty() && !args.dump_records.contains(&rec_num) {
+            continue;
+        }
+        let hdw =
+            record_datetime(&rec).and_then(|time| hdw_provider.get(rec.station_id, time).cloned());
+        let Some(hdw) = fit_for_dump(hdw, rec_num, args.strict)? else {
+            continue;
+        };
+        let Some(mut trace) = fit_for_dump(trace_fit_stages(&rec, options), rec_num, args.strict)?
+        else {
+            continue;
+        };
+        if !args.dump_ranges.is_empty() {
+            for stage in trace.stages.iter_mut() {
+                stage
+                    .ranges
+                    .retain(|range| args.dump_ranges.contains(&range.range_num));
+            }
+        }
+        traces.push(RecordTrace {
+            record: rec_num,
+            hdw,
+            trace,
+        });
+    }
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(
+        &mut writer,
+        &StageDump {
+            options,
+            records: traces,
+        },
+    )?;
+    writer.flush()?;
+    Ok(())
+}
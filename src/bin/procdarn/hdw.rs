@@ -0,0 +1,144 @@
+use crate::{BinResult, HdwSourceArgs};
+use backscatter_rs::utils::hdw_format::{lint, Severity};
+use backscatter_rs::utils::hdw_history::HdwHistory;
+use backscatter_rs::utils::stations::{Station, StationRegistry};
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, Subcommand};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct HdwArgs {
+    #[command(subcommand)]
+    command: HdwCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum HdwCommand {
+    /// Check hdw files for malformed lines, implausible parameters and misordered entries
+    Lint {
+        /// Hdw files to check. Files named hdw.dat.<code> are also checked against the station
+        /// id of <code>
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Exit with an error on warnings as well as errors
+        #[arg(long)]
+        deny_warnings: bool,
+    },
+    /// Print the hardware parameters of a radar valid at a given time
+    Show {
+        /// Station id, code or name
+        #[arg(value_parser = parse_station)]
+        station: Station,
+
+        /// Time as YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS [default: now]
+        #[arg(long, value_parser = parse_time)]
+        time: Option<NaiveDateTime>,
+    },
+    /// List the hdw entries of a radar and the parameters changed by each
+    History {
+        /// Station id, code or name
+        #[arg(value_parser = parse_station)]
+        station: Station,
+
+        /// Only list changes after this time, as YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS
+        #[arg(long, value_parser = parse_time)]
+        from: Option<NaiveDateTime>,
+
+        /// Only list changes up to this time, as YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS
+        #[arg(long, value_parser = parse_time)]
+        to: Option<NaiveDateTime>,
+    },
+}
+
+/// Returns whether all checks passed.
+pub fn run(args: &HdwArgs, hdw: &HdwSourceArgs) -> BinResult<bool> {
+    match &args.command {
+        HdwCommand::Lint {
+            files,
+            deny_warnings,
+        } => lint_files(files, *deny_warnings),
+        HdwCommand::Show { station, time } => {
+            let time = time.unwrap_or_else(|| chrono::Utc::now().naive_utc());
+            let history = HdwHistory::load(&hdw.source(), station.id)?;
+            let period = history
+                .at(time)
+                .ok_or_else(|| format!("No hdw entry for {} valid at {}", station.code, time))?;
+            println!(
+                "station: {} ({}, {})",
+                station.id, station.code, station.name
+            );
+            println!("valid from: {}", period.start);
+            match period.end {
+                Some(end) => println!("valid until: {}", end),
+                None => println!("valid until: present"),
+            }
+            for (name, value) in period.hdw.parameters() {
+                println!("{}: {}", name, value);
+            }
+            Ok(true)
+        }
+        HdwCommand::History { station, from, to } => {
+            let history = HdwHistory::load(&hdw.source(), station.id)?;
+            let from = from.unwrap_or(NaiveDateTime::MIN);
+            let to = to.unwrap_or(NaiveDateTime::MAX);
+            let changes = history.changes_between(from, to);
+            for period in history.periods() {
+                if period.start > to || period.end.is_some_and(|end| end <= from) {
+                    continue;
+                }
+                match period.end {
+                    Some(end) => println!("{} - {}", period.start, end),
+                    None => println!("{} - present", period.start),
+                }
+                for change in changes.iter().filter(|c| c.time == period.start) {
+                    println!("    {}: {} -> {}", change.parameter, change.old, change.new);
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn lint_files(files: &[PathBuf], deny_warnings: bool) -> BinResult<bool> {
+    let mut passed = true;
+    for path in files.iter() {
+        let station_id = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("hdw.dat."))
+            .and_then(|code| StationRegistry::embedded().by_code(code))
+            .map(|s| s.id);
+        let file =
+            File::open(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+        for issue in lint(BufReader::new(file), station_id) {
+            println!("{}:{}", path.display(), issue);
+            if issue.severity == Severity::Error || deny_warnings {
+                passed = false;
+            }
+        }
+    }
+    Ok(passed)
+}
+
+/// Finds a station by id, three-letter code or name
+fn parse_station(value: &str) -> Result<Station, String> {
+    let registry = StationRegistry::embedded();
+    let station = match value.parse::<i16>() {
+        Ok(id) => registry.by_id(id),
+        Err(_) => registry.by_code(value).or_else(|| registry.by_name(value)),
+    };
+    station
+        .cloned()
+        .ok_or_else(|| format!("Unknown station {}", value))
+}
+
+fn parse_time(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap())
+        })
+        .map_err(|_| format!("Expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS, got {}", value))
+}
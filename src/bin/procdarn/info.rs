@@ -0,0 +1,71 @@
+use crate::records::{RecordFile, RecordHeader};
+use crate::{BinResult, InputArgs};
+use backscatter_rs::utils::stations::StationRegistry;
+use clap::Args;
+use itertools::Itertools;
+
+#[derive(Args, Debug)]
+pub struct InfoArgs {
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+/// Prints the type, stations, time span, beams and channels of the records in a file.
+pub fn run(args: &InfoArgs) -> BinResult<()> {
+    let file = RecordFile::read(&args.input.infile)?;
+    let headers = file.headers();
+
+    println!("file: {}", args.input.infile.display());
+    println!("type: {}", file.file_type());
+    println!("records: {}", file.len());
+    if headers.is_empty() {
+        return Ok(());
+    }
+
+    let stations = headers.iter().map(|h| h.station_id).unique().map(|id| {
+        match StationRegistry::embedded().by_id(id) {
+            Some(station) => format!("{} ({}, {})", id, station.code, station.name),
+            None => format!("{} (unknown)", id),
+        }
+    });
+    println!("stations: {}", stations.format(", "));
+
+    let times: Vec<_> = headers.iter().filter_map(|h| h.time).collect();
+    if let (Some(start), Some(end)) = (times.iter().min(), times.iter().max()) {
+        println!("start: {}", start);
+        println!("end: {}", end);
+    }
+    let invalid_times = headers.len() - times.len();
+    if invalid_times > 0 {
+        println!("invalid times: {}", invalid_times);
+    }
+
+    println!(
+        "control programs: {}",
+        summarize(&headers, |h| h.control_program)
+    );
+    println!("beams: {}", summarize(&headers, |h| h.beam_num));
+    println!("channels: {}", summarize(&headers, |h| h.channel));
+    println!("ranges: {}", summarize(&headers, |h| h.num_ranges));
+
+    if let RecordFile::Fitacf(records) = &file {
+        let fitted: usize = records.iter().map(|rec| rec.range_list.data.len()).sum();
+        println!(
+            "fitted ranges: {} ({:.1} per record)",
+            fitted,
+            fitted as f64 / records.len() as f64
+        );
+    }
+    Ok(())
+}
+
+/// Sorted, comma-separated distinct values of a header field
+fn summarize(headers: &[RecordHeader], field: impl Fn(&RecordHeader) -> i16) -> String {
+    headers
+        .iter()
+        .map(field)
+        .unique()
+        .sorted()
+        .format(",")
+        .to_string()
+}
@@ -0,0 +1,243 @@
+//! Command line tool for processing SuperDARN data files.
+
+mod batch;
+mod convert;
+mod diff;
+mod fit;
+mod hdw;
+mod info;
+mod metrics;
+mod records;
+mod validate;
+
+use backscatter_rs::utils::compression::Compression;
+use backscatter_rs::utils::hdw::HdwSource;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use metrics::Metrics;
+use records::is_stdio;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+pub type BinResult<T, E = Box<dyn std::error::Error + Send + Sync>> = Result<T, E>;
+
+/// Exit codes follow diff(1): 1 when a check finds problems, 2 when the command fails.
+fn main() {
+    match bin_main() {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("error: {e}");
+            if let Some(e) = e.source() {
+                eprintln!("error: {e}")
+            }
+            std::process::exit(2);
+        }
+    }
+}
+
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (hdw ",
+    env!("HDW_VERSION"),
+    ")"
+);
+
+/// Fitting, inspection and conversion of SuperDARN data files
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, long_version = LONG_VERSION)]
+struct Cli {
+    #[command(flatten)]
+    hdw: HdwSourceArgs,
+
+    #[command(flatten)]
+    parallel: ParallelArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Fit rawacf files with FITACF 3.0
+    Fit(fit::FitArgs),
+    /// Summarize the records of a rawacf or fitacf file
+    Info(info::InfoArgs),
+    /// Rewrite a rawacf or fitacf file in another format
+    Convert(convert::ConvertArgs),
+    /// Inspect and check hdw files
+    Hdw(hdw::HdwArgs),
+    /// Compare the fitted parameters of two fitacf files
+    Diff(diff::DiffArgs),
+    /// Check that every record of rawacf files can be fitted
+    Validate(validate::ValidateArgs),
+}
+
+// File read by a subcommand
+#[derive(Args, Debug)]
+pub struct InputArgs {
+    /// Input file, or - for stdin
+    #[arg(short, long)]
+    pub infile: PathBuf,
+}
+
+// File written by a subcommand
+#[derive(Args, Debug)]
+pub struct OutputArgs {
+    /// Output file, or - for stdout
+    #[arg(short, long)]
+    pub outfile: PathBuf,
+
+    /// Compression of the output: none, bz2, gz or zst [default: from the output extension]
+    #[arg(long)]
+    pub compress: Option<Compression>,
+}
+
+impl OutputArgs {
+    pub fn compression(&self) -> Compression {
+        self.compress
+            .unwrap_or_else(|| Compression::from_extension(&self.outfile))
+    }
+}
+
+// Where to find the hardware parameters of the radars
+#[derive(Args, Debug)]
+pub struct HdwSourceArgs {
+    /// Directory of hdw files which take precedence over the embedded copies. Overrides the
+    /// BACKSCATTER_HDW_DIR environment variable
+    #[arg(long, global = true)]
+    pub hdw_dir: Option<PathBuf>,
+
+    /// Hdw file of the radar, used instead of any other hdw files
+    #[arg(long, global = true, conflicts_with = "hdw_dir")]
+    pub hdw_file: Option<PathBuf>,
+}
+
+impl HdwSourceArgs {
+    pub fn source(&self) -> HdwSource {
+        match (&self.hdw_file, &self.hdw_dir) {
+            (Some(path), _) => HdwSource::File(path.clone()),
+            (None, Some(dir)) => HdwSource::Dir(dir.clone()),
+            (None, None) => HdwSource::Default,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ParallelArgs {
+    /// Whether to process files in parallel, the records within each file, or the range gates
+    /// within each record
+    #[arg(long, global = true, value_enum, default_value_t = Parallelism::Records)]
+    pub parallelism: Parallelism,
+
+    /// Maximum number of records held in memory at once. Records are read, processed and
+    /// written in windows of this size; use 1 to pass on each record as soon as it is ready
+    /// [default: 256, or 1 when writing to stdout]
+    #[arg(long, global = true)]
+    pub window: Option<usize>,
+
+    /// Number of worker threads [default: one per CPU]
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+}
+
+impl ParallelArgs {
+    /// Window of records for an output. Records written to stdout are passed on one at a time,
+    /// so that programs reading from a pipe receive each record as soon as it is fitted.
+    pub fn window(&self, output: &Path) -> usize {
+        match self.window {
+            Some(window) => window,
+            None if is_stdio(output) => 1,
+            None => 256,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Parallelism {
+    /// Process many files at once, for the best throughput on large batches
+    Files,
+    /// Process many records at once, for the best throughput on whole files
+    Records,
+    /// Process the range gates of one record at a time, for the lowest latency per record
+    Ranges,
+}
+
+// Where and how much to log
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    /// Least severe level of messages logged to stderr: off, error, warn, info, debug or trace.
+    /// Overridden by the RUST_LOG environment variable
+    #[arg(long, global = true, default_value_t = LevelFilter::WARN)]
+    pub log_level: LevelFilter,
+
+    /// Format of messages logged to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Write the time spent in each processing stage, and the number of warnings and errors,
+    /// to stderr once done
+    #[arg(long, global = true)]
+    pub timings: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Sends log messages to stderr, returning the collector of timings if they were requested.
+fn init_logging(args: &LogArgs) -> Option<Metrics> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::default().add_directive(args.log_level.into()));
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let fmt = match args.log_format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+    let metrics = args.timings.then(Metrics::default);
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(filter))
+        .with(
+            metrics
+                .as_ref()
+                .map(|m| m.layer().with_filter(LevelFilter::DEBUG)),
+        )
+        .init();
+    metrics
+}
+
+/// Returns whether all checks passed.
+fn bin_main() -> BinResult<bool> {
+    let cli = Cli::parse();
+    let metrics = init_logging(&cli.log);
+
+    if let Some(threads) = cli.parallel.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    let result = match &cli.command {
+        Command::Fit(args) => fit::run(args, &cli.hdw, &cli.parallel).map(|_| true),
+        Command::Info(args) => info::run(args).map(|_| true),
+        Command::Convert(args) => convert::run(args).map(|_| true),
+        Command::Hdw(args) => hdw::run(args, &cli.hdw),
+        Command::Diff(args) => diff::run(args),
+        Command::Validate(args) => validate::run(args),
+    };
+    if let Some(metrics) = metrics {
+        metrics.report();
+    }
+    result
+}
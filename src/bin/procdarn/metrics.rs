@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Time spent in the spans of one name, and how many there were
+#[derive(Debug, Default, Clone, Copy)]
+struct SpanTotal {
+    count: usize,
+    busy: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    spans: HashMap<&'static str, SpanTotal>,
+    events: HashMap<Level, usize>,
+}
+
+/// Time a single span has spent entered, stored in the span's extensions
+#[derive(Debug, Default)]
+struct SpanTiming {
+    busy: Duration,
+    entered: Option<Instant>,
+}
+
+/// Collects the time spent in each span and the number of events of each level, for a summary
+/// at the end of a run.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    totals: Arc<Mutex<Totals>>,
+}
+
+impl Metrics {
+    pub fn layer(&self) -> MetricsLayer {
+        MetricsLayer {
+            totals: self.totals.clone(),
+        }
+    }
+
+    /// Writes the totals of every span, slowest first, and the number of warnings and errors
+    /// to stderr.
+    pub fn report(&self) {
+        let totals = match self.totals.lock() {
+            Ok(totals) => totals,
+            Err(_) => return,
+        };
+        let mut spans: Vec<(&&str, &SpanTotal)> = totals.spans.iter().collect();
+        spans.sort_by_key(|(_, total)| std::cmp::Reverse(total.busy));
+        eprintln!(
+            "{:<24} {:>10} {:>14} {:>14}",
+            "span", "count", "total (ms)", "mean (us)"
+        );
+        for (name, total) in spans {
+            eprintln!(
+                "{:<24} {:>10} {:>14.3} {:>14.3}",
+                name,
+                total.count,
+                total.busy.as_secs_f64() * 1e3,
+                total.busy.as_secs_f64() * 1e6 / total.count.max(1) as f64
+            );
+        }
+        for level in [Level::WARN, Level::ERROR] {
+            eprintln!(
+                "{}: {}",
+                level,
+                totals.events.get(&level).copied().unwrap_or_default()
+            );
+        }
+    }
+}
+
+pub struct MetricsLayer {
+    totals: Arc<Mutex<Totals>>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for MetricsLayer {
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanTiming::default());
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                timing.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                if let Some(entered) = timing.entered.take() {
+                    timing.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let busy = span
+            .extensions()
+            .get::<SpanTiming>()
+            .map_or(Duration::ZERO, |t| t.busy);
+        if let Ok(mut totals) = self.totals.lock() {
+            let total = totals.spans.entry(span.name()).or_default();
+            total.count += 1;
+            total.busy += busy;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if let Ok(mut totals) = self.totals.lock() {
+            *totals.events.entry(*event.metadata().level()).or_default() += 1;
+        }
+    }
+}
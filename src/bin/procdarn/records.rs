@@ -0,0 +1,178 @@
+use crate::BinResult;
+use backscatter_rs::utils::compression::{self, CompressedWriter, Compression};
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::ValueEnum;
+use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Path standing for stdin as an input, or stdout as an output
+pub const STDIO: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+/// Opens an input file, or stdin if the path is `-`, decompressing it if needed.
+pub fn open_input(path: &Path) -> BinResult<Box<dyn Read>> {
+    match is_stdio(path) {
+        true => Ok(compression::decompress(io::stdin().lock())?),
+        false => Ok(compression::open(path)?),
+    }
+}
+
+/// Creates an output file, or writes to stdout if the path is `-`.
+pub fn create_output(
+    path: &Path,
+    compression: Compression,
+) -> BinResult<CompressedWriter<Box<dyn Write>>> {
+    let writer: Box<dyn Write> = match is_stdio(path) {
+        true => Box::new(BufWriter::new(io::stdout().lock())),
+        false => {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| {
+                format!("Unable to create {}: {}", path.display(), e)
+            })?))
+        }
+    };
+    Ok(CompressedWriter::new(writer, compression)?)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    Rawacf,
+    Fitacf,
+}
+
+impl FileType {
+    /// Guesses the file type from the file name, e.g. 20210607.1801.00.cly.a.rawacf
+    pub fn from_path(path: &Path) -> Option<FileType> {
+        let name = path.file_name()?.to_str()?;
+        if name.contains("rawacf") {
+            Some(FileType::Rawacf)
+        } else if name.contains("fitacf") {
+            Some(FileType::Fitacf)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileType::Rawacf => write!(f, "rawacf"),
+            FileType::Fitacf => write!(f, "fitacf"),
+        }
+    }
+}
+
+/// The records of a data file.
+pub enum RecordFile {
+    Rawacf(Vec<RawacfRecord>),
+    Fitacf(Vec<FitacfRecord>),
+}
+
+impl RecordFile {
+    /// Reads a rawacf or fitacf file, which may be compressed, or stdin if the path is `-`.
+    /// Files whose type cannot be told from their name are read as rawacf, then as fitacf.
+    pub fn read(path: &Path) -> BinResult<RecordFile> {
+        let mut bytes = vec![];
+        open_input(path)?.read_to_end(&mut bytes)?;
+        let file = match FileType::from_path(path) {
+            Some(FileType::Rawacf) => RecordFile::Rawacf(RawacfRecord::read_records(&bytes[..])?),
+            Some(FileType::Fitacf) => RecordFile::Fitacf(FitacfRecord::read_records(&bytes[..])?),
+            None => {
+                match RawacfRecord::read_records(&bytes[..]) {
+                    Ok(records) => RecordFile::Rawacf(records),
+                    Err(_) => RecordFile::Fitacf(FitacfRecord::read_records(&bytes[..]).map_err(
+                        |_| format!("{} is not a rawacf or fitacf file", path.display()),
+                    )?),
+                }
+            }
+        };
+        Ok(file)
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self {
+            RecordFile::Rawacf(_) => FileType::Rawacf,
+            RecordFile::Fitacf(_) => FileType::Fitacf,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RecordFile::Rawacf(records) => records.len(),
+            RecordFile::Fitacf(records) => records.len(),
+        }
+    }
+
+    /// The header fields common to both record types, one entry per record.
+    pub fn headers(&self) -> Vec<RecordHeader> {
+        match self {
+            RecordFile::Rawacf(records) => records.iter().map(RecordHeader::from).collect(),
+            RecordFile::Fitacf(records) => records.iter().map(RecordHeader::from).collect(),
+        }
+    }
+}
+
+/// Identifying fields of a rawacf or fitacf record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordHeader {
+    pub station_id: i16,
+    pub control_program: i16,
+    pub beam_num: i16,
+    pub channel: i16,
+    pub num_ranges: i16,
+    /// None if the record holds an invalid date
+    pub time: Option<NaiveDateTime>,
+}
+
+impl From<&RawacfRecord> for RecordHeader {
+    fn from(rec: &RawacfRecord) -> Self {
+        RecordHeader {
+            station_id: rec.station_id,
+            control_program: rec.control_program,
+            beam_num: rec.beam_num,
+            channel: rec.channel,
+            num_ranges: rec.num_ranges,
+            time: datetime(
+                [
+                    rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second,
+                ],
+                rec.microsecond,
+            ),
+        }
+    }
+}
+
+impl From<&FitacfRecord> for RecordHeader {
+    fn from(rec: &FitacfRecord) -> Self {
+        RecordHeader {
+            station_id: rec.station_id,
+            control_program: rec.control_program,
+            beam_num: rec.beam_num,
+            channel: rec.channel,
+            num_ranges: rec.num_ranges,
+            time: datetime(
+                [
+                    rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second,
+                ],
+                rec.microsecond,
+            ),
+        }
+    }
+}
+
+/// Builds a timestamp from year, month, day, hour, minute and second fields
+fn datetime(fields: [i16; 6], microsecond: i32) -> Option<NaiveDateTime> {
+    let [year, month, day, hour, minute, second] = fields.map(|x| x as i32);
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)?.and_hms_micro_opt(
+        hour as u32,
+        minute as u32,
+        second as u32,
+        microsecond as u32,
+    )
+}
@@ -0,0 +1,82 @@
+use crate::batch::InputFilesArgs;
+use crate::records::open_input;
+use crate::BinResult;
+use backscatter_rs::fitting::validation::{non_finite_errors, rawacf_validation_errors};
+use backscatter_rs::utils::dmap_stream::RecordReader;
+use clap::Args;
+use dmap::formats::RawacfRecord;
+use std::path::Path;
+
+#[derive(Args, Debug)]
+pub struct ValidateArgs {
+    #[command(flatten)]
+    inputs: InputFilesArgs,
+
+    /// Only print the number of invalid records of each file, not their problems
+    #[arg(long)]
+    quiet: bool,
+}
+
+/// Returns whether every record of every file can be fitted.
+pub fn run(args: &ValidateArgs) -> BinResult<bool> {
+    let mut passed = true;
+    for (path, _) in args.inputs.files("rawacf")? {
+        passed &= validate_file(&path, args.quiet)?;
+    }
+    Ok(passed)
+}
+
+/// Prints the problems of each record of a file, and a count of the invalid records. Non-finite
+/// samples are printed as warnings, since fitting only rejects the lags holding them. A record
+/// which cannot be read ends the file, since the records after it cannot be found.
+fn validate_file(path: &Path, quiet: bool) -> BinResult<bool> {
+    let mut records = 0;
+    let mut invalid = 0;
+    let mut non_finite = 0;
+    let mut readable = true;
+    for rec in RecordReader::<RawacfRecord, _>::new(open_input(path)?) {
+        let rec = match rec {
+            Ok(rec) => rec,
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+                readable = false;
+                break;
+            }
+        };
+        let errors = rawacf_validation_errors(&rec);
+        let warnings = if errors.is_empty() {
+            non_finite_errors(&rec)
+        } else {
+            vec![]
+        };
+        if !errors.is_empty() {
+            invalid += 1;
+        }
+        if !warnings.is_empty() {
+            non_finite += 1;
+        }
+        if !quiet {
+            for err in errors {
+                println!("{}: record {}: {}", path.display(), records, err);
+            }
+            for warning in warnings {
+                println!(
+                    "{}: record {}: warning: {}",
+                    path.display(),
+                    records,
+                    warning
+                );
+            }
+        }
+        records += 1;
+    }
+    println!(
+        "{}: {} of {} records invalid, {} with non-finite samples{}",
+        path.display(),
+        invalid,
+        records,
+        non_finite,
+        if readable { "" } else { ", file unreadable" }
+    );
+    Ok(readable && invalid == 0)
+}
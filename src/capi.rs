@@ -0,0 +1,349 @@
+//! C interface to the FITACF 3.0 engine.
+//!
+//! C code describes a record with a `BsAcfInput` whose arrays stay owned by the caller, and
+//! receives a `BsFitResult` which is owned by this library until it is released with
+//! `bs_fit_result_free`. Every function returns a `BsStatus`; when it is not `BS_STATUS_OK`,
+//! `bs_last_error` describes the failure.
+//!
+//! `cargo build --release --features capi` builds the shared library, whose functions are
+//! declared in include/backscatter_rs.h. Run scripts/update_c_header.sh after changing them.
+use crate::fitting::acf_data::AcfRecord;
+use crate::fitting::fitacf3::determinations::RangeFit;
+use crate::fitting::fitacf3::fitacf_v3::{fit_acf_data, FitOptions, Fitacf3Error};
+use crate::utils::hdw::HdwInfo;
+use chrono::{DateTime, NaiveDateTime};
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// Result of a call into the library
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BsStatus {
+    Ok = 0,
+    /// A required pointer argument was null
+    NullPointer = 1,
+    /// The input arrays or parameters are inconsistent
+    InvalidInput = 2,
+    /// No hardware parameters were found for the radar
+    HdwNotFound = 3,
+    /// The fitting routines were unable to fit the record
+    FitFailed = 4,
+    /// An internal error occurred
+    Internal = 5,
+}
+
+/// The ACF data of one integration period. All arrays are owned by the caller and are only
+/// read during the call.
+#[repr(C)]
+pub struct BsAcfInput {
+    pub num_averages: i16,
+    pub num_pulses: i16,
+    pub num_lags: i16,
+    pub num_ranges: i16,
+    /// Lag to the first range, in microseconds
+    pub lag_to_first_range: i16,
+    /// Sample separation, in microseconds
+    pub sample_separation: i16,
+    /// Length of a transmitted pulse, in microseconds
+    pub tx_pulse_length: i16,
+    /// Basic lag time of the pulse sequence, in microseconds
+    pub multi_pulse_increment: i16,
+    pub offset: i16,
+    pub channel: i16,
+    pub beam_num: i16,
+    /// Transmitted frequency, in kHz
+    pub tx_freq: i16,
+    pub search_noise: f32,
+    /// `num_pulses` pulse positions, in units of `multi_pulse_increment`
+    pub pulse_table: *const i16,
+    /// `num_lags` pairs of pulses, flattened row-major
+    pub lag_table: *const i16,
+    /// `num_ranges` lag zero powers
+    pub lag_zero_power: *const f32,
+    /// Range gates with ACF data
+    pub range_list: *const i16,
+    pub range_list_len: usize,
+    /// `[range_list_len, num_lags, 2]` ACF values, flattened row-major
+    pub acfs: *const f32,
+    /// `[range_list_len, num_lags, 2]` XCF values, or null if there are none
+    pub xcfs: *const f32,
+}
+
+/// Hardware parameters of a radar, as listed in its hdw file
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BsHdw {
+    pub station_id: i16,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub altitude: f32,
+    pub boresight: f32,
+    pub boresight_shift: f32,
+    pub beam_separation: f32,
+    pub velocity_sign: f32,
+    pub phase_sign: f32,
+    pub tdiff_a: f32,
+    pub tdiff_b: f32,
+    pub intf_offset_x: f32,
+    pub intf_offset_y: f32,
+    pub intf_offset_z: f32,
+    pub rx_rise_time: f32,
+    pub rx_atten_step: f32,
+    pub attenuation_stages: f32,
+    pub max_num_ranges: i16,
+    pub max_num_beams: i16,
+}
+
+impl From<&HdwInfo> for BsHdw {
+    fn from(hdw: &HdwInfo) -> Self {
+        BsHdw {
+            station_id: hdw.station_id,
+            latitude: hdw.latitude,
+            longitude: hdw.longitude,
+            altitude: hdw.altitude,
+            boresight: hdw.boresight,
+            boresight_shift: hdw.boresight_shift,
+            beam_separation: hdw.beam_separation,
+            velocity_sign: hdw.velocity_sign,
+            phase_sign: hdw.phase_sign,
+            tdiff_a: hdw.tdiff_a,
+            tdiff_b: hdw.tdiff_b,
+            intf_offset_x: hdw.intf_offset_x,
+            intf_offset_y: hdw.intf_offset_y,
+            intf_offset_z: hdw.intf_offset_z,
+            rx_rise_time: hdw.rx_rise_time,
+            rx_atten_step: hdw.rx_atten_step,
+            attenuation_stages: hdw.attenuation_stages,
+            max_num_ranges: hdw.max_num_ranges,
+            max_num_beams: hdw.max_num_beams,
+        }
+    }
+}
+
+impl From<&BsHdw> for HdwInfo {
+    fn from(hdw: &BsHdw) -> Self {
+        HdwInfo {
+            station_id: hdw.station_id,
+            valid_from: NaiveDateTime::MIN,
+            latitude: hdw.latitude,
+            longitude: hdw.longitude,
+            altitude: hdw.altitude,
+            boresight: hdw.boresight,
+            boresight_shift: hdw.boresight_shift,
+            beam_separation: hdw.beam_separation,
+            velocity_sign: hdw.velocity_sign,
+            phase_sign: hdw.phase_sign,
+            tdiff_a: hdw.tdiff_a,
+            tdiff_b: hdw.tdiff_b,
+            intf_offset_x: hdw.intf_offset_x,
+            intf_offset_y: hdw.intf_offset_y,
+            intf_offset_z: hdw.intf_offset_z,
+            rx_rise_time: hdw.rx_rise_time,
+            rx_atten_step: hdw.rx_atten_step,
+            attenuation_stages: hdw.attenuation_stages,
+            max_num_ranges: hdw.max_num_ranges,
+            max_num_beams: hdw.max_num_beams,
+        }
+    }
+}
+
+/// Fitted parameters of a record. Owned by the library; release with `bs_fit_result_free`.
+#[repr(C)]
+pub struct BsFitResult {
+    /// Noise power used as the ACF cutoff
+    pub sky_noise: f32,
+    /// `num_ranges` of the input lag zero powers relative to the noise, in dB
+    pub lag_zero_power_db: *mut f32,
+    pub lag_zero_power_len: usize,
+    /// Fitted parameters of each range which survived filtering
+    pub ranges: *mut RangeFit,
+    pub ranges_len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(status: BsStatus, msg: impl Into<String>) -> BsStatus {
+    let msg = CString::new(msg.into().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+    status
+}
+
+/// Runs `f`, turning a panic into `BS_STATUS_INTERNAL` so that it does not cross the FFI
+/// boundary.
+fn guard(f: impl FnOnce() -> BsStatus) -> BsStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(status) => status,
+        Err(_) => fail(BsStatus::Internal, "Panic while fitting record"),
+    }
+}
+
+/// Borrows a C array as a slice, allowing a null pointer only for an empty array.
+unsafe fn c_slice<'a, T>(ptr: *const T, len: usize, name: &str) -> Result<&'a [T], BsStatus> {
+    if len == 0 {
+        Ok(&[])
+    } else if ptr.is_null() {
+        Err(fail(BsStatus::NullPointer, format!("{name} is null")))
+    } else {
+        Ok(slice::from_raw_parts(ptr, len))
+    }
+}
+
+fn count(value: i16, name: &str) -> Result<usize, BsStatus> {
+    usize::try_from(value).map_err(|_| {
+        fail(
+            BsStatus::InvalidInput,
+            format!("{name} must not be negative"),
+        )
+    })
+}
+
+/// Copies the caller's arrays into an `AcfRecord`.
+unsafe fn acf_record(input: &BsAcfInput) -> Result<AcfRecord, BsStatus> {
+    let num_lags = count(input.num_lags, "num_lags")?;
+    let num_ranges = count(input.num_ranges, "num_ranges")?;
+    let acf_len = input.range_list_len * num_lags * 2;
+    let range_list = c_slice(input.range_list, input.range_list_len, "range_list")?;
+    if range_list
+        .iter()
+        .any(|&r| r < 0 || r as usize >= num_ranges)
+    {
+        Err(fail(
+            BsStatus::InvalidInput,
+            "range_list contains a range outside of num_ranges",
+        ))?
+    }
+    let xcfs = if input.xcfs.is_null() {
+        None
+    } else {
+        Some(c_slice(input.xcfs, acf_len, "xcfs")?.to_vec())
+    };
+    Ok(AcfRecord {
+        num_averages: input.num_averages,
+        lag_to_first_range: input.lag_to_first_range,
+        sample_separation: input.sample_separation,
+        tx_pulse_length: input.tx_pulse_length,
+        multi_pulse_increment: input.multi_pulse_increment,
+        offset: input.offset,
+        channel: input.channel,
+        beam_num: input.beam_num,
+        tx_freq: input.tx_freq,
+        search_noise: input.search_noise,
+        num_ranges: input.num_ranges,
+        num_lags: input.num_lags,
+        pulse_table: c_slice(
+            input.pulse_table,
+            count(input.num_pulses, "num_pulses")?,
+            "pulse_table",
+        )?
+        .to_vec(),
+        lag_table: c_slice(input.lag_table, num_lags * 2, "lag_table")?.to_vec(),
+        lag_zero_power: c_slice(input.lag_zero_power, num_ranges, "lag_zero_power")?.to_vec(),
+        range_list: range_list.to_vec(),
+        acfs: c_slice(input.acfs, acf_len, "acfs")?.to_vec(),
+        xcfs,
+    })
+}
+
+/// Fits one record with the FITACF 3.0 algorithm. On success, `*out` points to a result which
+/// the caller must release with `bs_fit_result_free`; otherwise `*out` is set to null.
+///
+/// # Safety
+/// `input` and `hdw` must point to valid structs whose arrays hold the number of elements
+/// documented on `BsAcfInput`, and `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bs_fit_acf(
+    input: *const BsAcfInput,
+    hdw: *const BsHdw,
+    out: *mut *mut BsFitResult,
+) -> BsStatus {
+    guard(|| {
+        if out.is_null() {
+            return fail(BsStatus::NullPointer, "out is null");
+        }
+        *out = ptr::null_mut();
+        if input.is_null() || hdw.is_null() {
+            return fail(BsStatus::NullPointer, "input or hdw is null");
+        }
+        let record = match acf_record(&*input) {
+            Ok(record) => record,
+            Err(status) => return status,
+        };
+        let hdw = HdwInfo::from(&*hdw);
+        let result = match fit_acf_data(&record, &hdw, &FitOptions::default()) {
+            Ok(result) => result,
+            Err(e @ Fitacf3Error::Invalid(_)) => {
+                return fail(BsStatus::InvalidInput, e.to_string())
+            }
+            Err(e) => return fail(BsStatus::FitFailed, e.to_string()),
+        };
+
+        let lag_zero_power_len = result.lag_zero_power_db.len();
+        let ranges_len = result.ranges.len();
+        *out = Box::into_raw(Box::new(BsFitResult {
+            sky_noise: result.sky_noise,
+            lag_zero_power_db: Box::into_raw(result.lag_zero_power_db.into_boxed_slice())
+                as *mut f32,
+            lag_zero_power_len,
+            ranges: Box::into_raw(result.ranges.into_boxed_slice()) as *mut RangeFit,
+            ranges_len,
+        }));
+        BsStatus::Ok
+    })
+}
+
+/// Releases a result returned by `bs_fit_acf`. Passing null does nothing.
+///
+/// # Safety
+/// `result` must be null or a pointer returned by `bs_fit_acf` which has not yet been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bs_fit_result_free(result: *mut BsFitResult) {
+    if result.is_null() {
+        return;
+    }
+    let result = Box::from_raw(result);
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        result.lag_zero_power_db,
+        result.lag_zero_power_len,
+    )));
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        result.ranges,
+        result.ranges_len,
+    )));
+}
+
+/// Looks up the hardware parameters of a radar from the bundled hdw files, writing them to
+/// `*out`. `time` is in seconds since the Unix epoch, UTC.
+///
+/// # Safety
+/// `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bs_hdw_lookup(station_id: i16, time: i64, out: *mut BsHdw) -> BsStatus {
+    guard(|| {
+        if out.is_null() {
+            return fail(BsStatus::NullPointer, "out is null");
+        }
+        let datetime = match DateTime::from_timestamp(time, 0) {
+            Some(datetime) => datetime.naive_utc(),
+            None => return fail(BsStatus::InvalidInput, "Invalid time"),
+        };
+        match HdwInfo::new(station_id, datetime) {
+            Ok(hdw) => {
+                *out = BsHdw::from(&hdw);
+                BsStatus::Ok
+            }
+            Err(e) => fail(BsStatus::HdwNotFound, e.details),
+        }
+    })
+}
+
+/// Describes the most recent failure on the calling thread. The string is owned by the
+/// library and is valid until the next call into it from the same thread.
+#[no_mangle]
+pub extern "C" fn bs_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
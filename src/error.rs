@@ -19,3 +19,5 @@ impl BackscatterError {
         }
     }
 }
+
+impl std::error::Error for BackscatterError {}
@@ -0,0 +1,206 @@
+use dmap::formats::RawacfRecord;
+
+/// The ACF data of a single integration period, along with the radar parameters needed to fit
+/// it. Implementing this trait lets data from any source go through the fitting routines.
+///
+/// Tables are flattened in row-major order: the lag table is `[num_lags, 2]`, and the ACFs and
+/// XCFs are `[num ranges in range_list, num_lags, 2]` with the last axis being (real, imag).
+pub trait AcfData: Sync {
+    fn num_averages(&self) -> i16;
+    fn num_pulses(&self) -> i16;
+    fn num_lags(&self) -> i16;
+    fn num_ranges(&self) -> i16;
+    /// Lag to the first range, in microseconds
+    fn lag_to_first_range(&self) -> i16;
+    /// Sample separation, in microseconds
+    fn sample_separation(&self) -> i16;
+    /// Length of a transmitted pulse, in microseconds
+    fn tx_pulse_length(&self) -> i16;
+    /// Basic lag time of the pulse sequence, in microseconds
+    fn multi_pulse_increment(&self) -> i16;
+    /// Offset between the channels of a stereo radar, in microseconds
+    fn offset(&self) -> i16;
+    fn channel(&self) -> i16;
+    fn beam_num(&self) -> i16;
+    /// Transmitted frequency, in kHz
+    fn tx_freq(&self) -> i16;
+    fn search_noise(&self) -> f32;
+    fn pulse_table(&self) -> &[i16];
+    fn lag_table(&self) -> &[i16];
+    fn lag_zero_power(&self) -> &[f32];
+    fn range_list(&self) -> &[i16];
+    fn acfs(&self) -> &[f32];
+    fn xcfs(&self) -> Option<&[f32]>;
+}
+
+impl AcfData for RawacfRecord {
+    fn num_averages(&self) -> i16 {
+        self.num_averages
+    }
+    fn num_pulses(&self) -> i16 {
+        self.num_pulses
+    }
+    fn num_lags(&self) -> i16 {
+        self.num_lags
+    }
+    fn num_ranges(&self) -> i16 {
+        self.num_ranges
+    }
+    fn lag_to_first_range(&self) -> i16 {
+        self.lag_to_first_range
+    }
+    fn sample_separation(&self) -> i16 {
+        self.sample_separation
+    }
+    fn tx_pulse_length(&self) -> i16 {
+        self.tx_pulse_length
+    }
+    fn multi_pulse_increment(&self) -> i16 {
+        self.multi_pulse_increment
+    }
+    fn offset(&self) -> i16 {
+        self.offset
+    }
+    fn channel(&self) -> i16 {
+        self.channel
+    }
+    fn beam_num(&self) -> i16 {
+        self.beam_num
+    }
+    fn tx_freq(&self) -> i16 {
+        self.tx_freq
+    }
+    fn search_noise(&self) -> f32 {
+        self.search_noise
+    }
+    fn pulse_table(&self) -> &[i16] {
+        &self.pulse_table.data
+    }
+    fn lag_table(&self) -> &[i16] {
+        &self.lag_table.data
+    }
+    fn lag_zero_power(&self) -> &[f32] {
+        &self.lag_zero_power.data
+    }
+    fn range_list(&self) -> &[i16] {
+        &self.range_list.data
+    }
+    fn acfs(&self) -> &[f32] {
+        &self.acfs.data
+    }
+    fn xcfs(&self) -> Option<&[f32]> {
+        self.xcfs.as_ref().map(|x| x.data.as_slice())
+    }
+}
+
+/// Crate-owned container of ACF data, for fitting data which does not come from a rawacf file,
+/// e.g. from acquisition software or simulations.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AcfRecord {
+    pub num_averages: i16,
+    pub lag_to_first_range: i16,
+    pub sample_separation: i16,
+    pub tx_pulse_length: i16,
+    pub multi_pulse_increment: i16,
+    pub offset: i16,
+    pub channel: i16,
+    pub beam_num: i16,
+    pub tx_freq: i16,
+    pub search_noise: f32,
+    pub num_ranges: i16,
+    /// Number of lags in the ACFs. The lag table may hold more rows than this, as rawacf files
+    /// list an alternate lag zero after the fitted lags.
+    pub num_lags: i16,
+    pub pulse_table: Vec<i16>,
+    pub lag_table: Vec<i16>,
+    pub lag_zero_power: Vec<f32>,
+    pub range_list: Vec<i16>,
+    pub acfs: Vec<f32>,
+    pub xcfs: Option<Vec<f32>>,
+}
+
+impl AcfData for AcfRecord {
+    fn num_averages(&self) -> i16 {
+        self.num_averages
+    }
+    fn num_pulses(&self) -> i16 {
+        self.pulse_table.len() as i16
+    }
+    fn num_lags(&self) -> i16 {
+        self.num_lags
+    }
+    fn num_ranges(&self) -> i16 {
+        self.num_ranges
+    }
+    fn lag_to_first_range(&self) -> i16 {
+        self.lag_to_first_range
+    }
+    fn sample_separation(&self) -> i16 {
+        self.sample_separation
+    }
+    fn tx_pulse_length(&self) -> i16 {
+        self.tx_pulse_length
+    }
+    fn multi_pulse_increment(&self) -> i16 {
+        self.multi_pulse_increment
+    }
+    fn offset(&self) -> i16 {
+        self.offset
+    }
+    fn channel(&self) -> i16 {
+        self.channel
+    }
+    fn beam_num(&self) -> i16 {
+        self.beam_num
+    }
+    fn tx_freq(&self) -> i16 {
+        self.tx_freq
+    }
+    fn search_noise(&self) -> f32 {
+        self.search_noise
+    }
+    fn pulse_table(&self) -> &[i16] {
+        &self.pulse_table
+    }
+    fn lag_table(&self) -> &[i16] {
+        &self.lag_table
+    }
+    fn lag_zero_power(&self) -> &[f32] {
+        &self.lag_zero_power
+    }
+    fn range_list(&self) -> &[i16] {
+        &self.range_list
+    }
+    fn acfs(&self) -> &[f32] {
+        &self.acfs
+    }
+    fn xcfs(&self) -> Option<&[f32]> {
+        self.xcfs.as_deref()
+    }
+}
+
+impl From<&RawacfRecord> for AcfRecord {
+    fn from(rec: &RawacfRecord) -> Self {
+        AcfRecord {
+            num_averages: rec.num_averages,
+            lag_to_first_range: rec.lag_to_first_range,
+            sample_separation: rec.sample_separation,
+            tx_pulse_length: rec.tx_pulse_length,
+            multi_pulse_increment: rec.multi_pulse_increment,
+            offset: rec.offset,
+            channel: rec.channel,
+            beam_num: rec.beam_num,
+            tx_freq: rec.tx_freq,
+            search_noise: rec.search_noise,
+            num_ranges: rec.num_ranges,
+            num_lags: rec.num_lags,
+            pulse_table: rec.pulse_table.data.clone(),
+            lag_table: rec.lag_table.data.clone(),
+            lag_zero_power: rec.lag_zero_power.data.clone(),
+            range_list: rec.range_list.data.clone(),
+            acfs: rec.acfs.data.clone(),
+            xcfs: rec.xcfs.as_ref().map(|x| x.data.clone()),
+        }
+    }
+}
@@ -1,57 +1,63 @@
+use crate::fitting::acf_data::AcfData;
 use crate::fitting::fitacf3::fitacf_v3::{
     Fitacf3Error, ALPHA_CUTOFF, FLUCTUATION_CUTOFF_COEFFICIENT, MIN_LAGS,
 };
-use crate::fitting::fitacf3::fitstruct::{LagNode, RangeNode};
-use dmap::formats::RawacfRecord;
+use crate::fitting::fitacf3::fitstruct::{move_rejected, LagNode, LagRejection, RangeNode};
+use crate::fitting::fitacf3::fitting::for_each_range;
 use is_close::is_close;
 
 /// passing
-pub fn mark_bad_samples(rec: &RawacfRecord) -> Vec<i32> {
+pub fn mark_bad_samples(rec: &impl AcfData) -> Vec<i32> {
     let mut pulses_in_us: Vec<i32> = rec
-        .pulse_table
-        .data
+        .pulse_table()
         .iter()
-        .map(|&p| p as i32 * rec.multi_pulse_increment as i32)
+        .map(|&p| p as i32 * rec.multi_pulse_increment() as i32)
         .collect();
 
-    if rec.offset != 0 {
-        if rec.channel == 1 {
+    if rec.offset() != 0 {
+        if rec.channel() == 1 {
             let pulses_stereo: Vec<i32> = pulses_in_us
                 .iter()
-                .map(|&p| p - rec.offset as i32)
+                .map(|&p| p - rec.offset() as i32)
                 .collect();
             pulses_in_us.extend(pulses_stereo);
-        } else if rec.channel == 2 {
+        } else if rec.channel() == 2 {
             let pulses_stereo: Vec<i32> = pulses_in_us
                 .iter()
-                .map(|&p| p + rec.offset as i32)
+                .map(|&p| p + rec.offset() as i32)
                 .collect();
             pulses_in_us.extend(pulses_stereo);
         }
     }
     pulses_in_us.sort();
 
-    let mut ts = rec.lag_to_first_range as i32;
+    // Samples would never advance past a pulse
+    let sample_separation = rec.sample_separation() as i32;
+    if sample_separation <= 0 {
+        return vec![];
+    }
+    let mut ts = rec.lag_to_first_range() as i32;
     let mut t1;
     let mut t2;
     let mut sample = 0;
     let mut bad_samples = vec![];
 
     for pulse_us in pulses_in_us {
-        t1 = pulse_us - rec.tx_pulse_length as i32 / 2;
-        t2 = t1 + 3 * rec.tx_pulse_length as i32 / 2 + 100;
+        t1 = pulse_us - rec.tx_pulse_length() as i32 / 2;
+        t2 = t1 + 3 * rec.tx_pulse_length() as i32 / 2 + 100;
 
-        // Start incrementing the sample until we find a sample that lies within a pulse
-        while ts < t1 {
-            sample += 1;
-            ts += rec.sample_separation as i32;
+        // Skip ahead to the first sample that lies within a pulse
+        if ts < t1 {
+            let skipped = (t1 - ts + sample_separation - 1) / sample_separation;
+            sample += skipped;
+            ts += skipped * sample_separation;
         }
 
         // Blank all samples within the pulse duration
         while (ts >= t1) && (ts <= t2) {
             bad_samples.push(sample);
             sample += 1;
-            ts += rec.sample_separation as i32;
+            ts += sample_separation;
         }
     }
     bad_samples
@@ -59,66 +65,59 @@ pub fn mark_bad_samples(rec: &RawacfRecord) -> Vec<i32> {
 
 /// passing
 pub fn filter_tx_overlapped_lags(
-    rec: &RawacfRecord,
-    lags: Vec<LagNode>,
-    ranges: &mut Vec<RangeNode>,
+    rec: &impl AcfData,
+    lags: &[LagNode],
+    ranges: &mut [RangeNode],
+    parallel: bool,
 ) {
     let bad_samples = mark_bad_samples(rec);
-    for range_node in ranges {
-        let mut bad_indices = vec![];
-        for (idx, lag) in lags.iter().enumerate() {
-            let sample_1 = lag.sample_base_1 + range_node.range_num as i32;
-            let sample_2 = lag.sample_base_2 + range_node.range_num as i32;
-            if bad_samples.contains(&sample_1) || bad_samples.contains(&sample_2) {
-                bad_indices.push(idx);
-            }
-        }
-        for i in bad_indices.iter().rev() {
-            range_node.powers.remove(*i);
-            range_node.phases.remove(*i);
-            range_node.elev.remove(*i);
-            range_node.power_alpha_2.remove(*i);
-            range_node.phase_alpha_2.remove(*i);
-        }
-    }
+    for_each_range(ranges, parallel, |range_node| {
+        let rejected: Vec<bool> = lags
+            .iter()
+            .map(|lag| {
+                let sample_1 = lag.sample_base_1 + range_node.range_num as i32;
+                let sample_2 = lag.sample_base_2 + range_node.range_num as i32;
+                bad_samples.contains(&sample_1) || bad_samples.contains(&sample_2)
+            })
+            .collect();
+        range_node.reject_power_lags(&rejected, LagRejection::TxOverlap);
+        range_node.reject_phase_lags(&rejected, LagRejection::TxOverlap);
+    });
 }
 
 /// passing
-pub fn filter_infinite_lags(ranges: &mut Vec<RangeNode>) {
-    for range in ranges {
-        let mut infinite_indices = vec![];
-        for i in 0..range.powers.ln_power.len() {
-            if !range.powers.ln_power[i].is_finite() {
-                infinite_indices.push(i);
-            }
-        }
-        for i in infinite_indices.iter().rev() {
-            range.powers.remove(*i);
-            range.power_alpha_2.remove(*i);
-        }
-    }
+pub fn filter_infinite_lags(ranges: &mut [RangeNode], parallel: bool) {
+    for_each_range(ranges, parallel, |range| {
+        let rejected: Vec<bool> = range
+            .powers
+            .ln_power
+            .iter()
+            .map(|p| !p.is_finite())
+            .collect();
+        range.reject_power_lags(&rejected, LagRejection::NonFinite);
+    });
 }
 
 /// passing
-pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
-    if rec.num_averages <= 0 {
+pub fn filter_low_power_lags(rec: &impl AcfData, ranges: &mut [RangeNode], parallel: bool) {
+    if rec.num_averages() <= 0 {
         return;
     }
-    for range in ranges {
+    for_each_range(ranges, parallel, |range| {
         let range_num = range.range_num;
         if range.powers.ln_power.is_empty() {
-            continue;
+            return;
         }
         let log_sigma_fluc = (FLUCTUATION_CUTOFF_COEFFICIENT as f32
-            * rec.lag_zero_power.data[range_num]
-            / ((2 * rec.num_averages) as f32).sqrt())
+            * rec.lag_zero_power()[range_num]
+            / (2.0 * rec.num_averages() as f32).sqrt())
         .ln();
-        let mut bad_indices = vec![];
-        let mut cutoff_lag = rec.num_lags as usize + 1;
+        let mut rejected = vec![false; range.powers.ln_power.len()];
+        let mut cutoff_lag = rec.num_lags() as usize + 1;
 
-        for idx in 0..range.powers.ln_power.len() {
-            if idx > cutoff_lag as usize {
-                bad_indices.push(idx);
+        for (idx, reject) in rejected.iter_mut().enumerate() {
+            if idx > cutoff_lag {
+                *reject = true;
             } else {
                 let log_power = range.powers.ln_power[idx];
                 let alpha_2 = range.power_alpha_2[idx];
@@ -127,32 +126,33 @@ pub fn filter_low_power_lags(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>) {
                         || is_close!(log_power, log_sigma_fluc as f64))
                 {
                     cutoff_lag = idx;
-                    bad_indices.push(idx);
+                    *reject = true;
                 }
             }
         }
-        for i in bad_indices.iter().rev() {
-            range.powers.remove(*i);
-            // range.phases.remove(*i);
-            // range.elev.remove(*i);
-            range.power_alpha_2.remove(*i);
-        }
-    }
+        range.reject_power_lags(&rejected, LagRejection::LowPower);
+    });
 }
 
 /// passing
-pub fn filter_bad_acfs(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>, noise_power: f32) {
-    if rec.num_averages <= 0 {
+/// Rejected ranges are moved into `spare`, so that their allocations can be reused.
+pub fn filter_bad_acfs(
+    rec: &impl AcfData,
+    ranges: &mut Vec<RangeNode>,
+    noise_power: f32,
+    spare: &mut Vec<RangeNode>,
+) {
+    if rec.num_averages() <= 0 {
         return;
     }
     let cutoff_power = noise_power * 2.0;
-    let mut bad_indices = vec![];
+    let mut rejected = vec![false; ranges.len()];
     for (idx, range) in ranges.iter().enumerate() {
         let range_num = range.range_num as usize;
-        let power = rec.lag_zero_power.data[range_num];
+        let power = rec.lag_zero_power()[range_num];
         let num_powers = range.powers.ln_power.len();
         if (power <= cutoff_power) || (num_powers < MIN_LAGS as usize) {
-            bad_indices.push(idx);
+            rejected[idx] = true;
         } else {
             let power_value = range.powers.ln_power[0];
             let mut all_equal = true;
@@ -162,18 +162,20 @@ pub fn filter_bad_acfs(rec: &RawacfRecord, ranges: &mut Vec<RangeNode>, noise_po
                 }
             }
             if all_equal {
-                bad_indices.push(idx);
+                rejected[idx] = true;
             }
         }
     }
-    for idx in bad_indices.iter().rev() {
-        ranges.remove(*idx);
-    }
+    move_rejected(ranges, &rejected, spare);
 }
 
 /// presumed passing
-pub fn filter_bad_fits(ranges: &mut Vec<RangeNode>) -> Result<(), Fitacf3Error> {
-    let mut bad_indices = vec![];
+/// Rejected ranges are moved into `spare`, so that their allocations can be reused.
+pub fn filter_bad_fits(
+    ranges: &mut Vec<RangeNode>,
+    spare: &mut Vec<RangeNode>,
+) -> Result<(), Fitacf3Error> {
+    let mut rejected = vec![false; ranges.len()];
     for (idx, range) in ranges.iter().enumerate() {
         if (range
             .phase_fit
@@ -204,12 +206,65 @@ pub fn filter_bad_fits(ranges: &mut Vec<RangeNode>) -> Result<(), Fitacf3Error>
                 .slope
                 == 0.0)
         {
-            bad_indices.push(idx);
+            rejected[idx] = true;
         }
     }
-    bad_indices.sort();
-    for idx in bad_indices.iter().rev() {
-        ranges.remove(*idx);
-    }
+    move_rejected(ranges, &rejected, spare);
     Ok(())
 }
+
+/// Rejects the lags whose residuals from the power and phase fits exceed `clip_sigma` standard
+/// deviations. Lags are only rejected while a range keeps at least `MIN_LAGS` points, and the
+/// number of rejected lags is added to each range's count. Returns the number of lags rejected.
+///
+/// Power is only clipped against the linear (exponential decay) fit. The quadratic fit uses the
+/// same lags, so it is refit without the lags clipped here, but lags which are outliers from the
+/// quadratic model alone are kept: one of the two models is usually a poor description of the
+/// decay, and clipping against it would reject good lags.
+pub fn filter_outlier_lags(ranges: &mut [RangeNode], clip_sigma: f64) -> usize {
+    let mut total_rejected = 0;
+    for range in ranges {
+        let mut rejected_powers = vec![false; range.powers.ln_power.len()];
+        if let Some(fit) = range.lin_pwr_fit.as_ref() {
+            for (idx, reject) in rejected_powers.iter_mut().enumerate() {
+                let log_power = range.powers.ln_power[idx];
+                // Convert the power sigma to a sigma in log power, as used for the fit errors
+                let sigma = range.powers.std_dev[idx] / log_power.exp();
+                if sigma == 0.0 {
+                    continue;
+                }
+                let residual = log_power - (fit.intercept + fit.slope * range.powers.t[idx]);
+                if (residual / sigma).abs() > clip_sigma {
+                    *reject = true;
+                }
+            }
+        }
+        let num_rejected = rejected_powers.iter().filter(|&&r| r).count();
+        if range.powers.ln_power.len() >= MIN_LAGS as usize + num_rejected {
+            range.reject_power_lags(&rejected_powers, LagRejection::Clipped);
+            range.num_clipped_lags += num_rejected;
+            total_rejected += num_rejected;
+        }
+
+        let mut rejected_phases = vec![false; range.phases.phases.len()];
+        if let Some(fit) = range.phase_fit.as_ref() {
+            for (idx, reject) in rejected_phases.iter_mut().enumerate() {
+                let sigma = range.phases.std_dev[idx];
+                if sigma == 0.0 {
+                    continue;
+                }
+                let residual = range.phases.phases[idx] - fit.slope * range.phases.t[idx];
+                if (residual / sigma).abs() > clip_sigma {
+                    *reject = true;
+                }
+            }
+        }
+        let num_rejected = rejected_phases.iter().filter(|&&r| r).count();
+        if range.phases.phases.len() >= MIN_LAGS as usize + num_rejected {
+            range.reject_phase_lags(&rejected_phases, LagRejection::Clipped);
+            range.num_clipped_lags += num_rejected;
+            total_rejected += num_rejected;
+        }
+    }
+    total_rejected
+}
@@ -1,14 +1,18 @@
+use crate::fitting::acf_data::AcfData;
 use crate::fitting::fitacf3::fitstruct::{LagNode, RangeNode};
 
-use crate::fitting::fitacf3::determinations::determinations;
+use crate::fitting::fitacf3::determinations::{determinations, fit_results, FitResult};
 use crate::fitting::fitacf3::filtering;
 use crate::fitting::fitacf3::fitting;
+use crate::fitting::validation::{validate, ValidationError};
 use crate::utils::hdw::HdwInfo;
 use dmap::formats::{FitacfRecord, RawacfRecord};
+use rayon::prelude::*;
 use std::error::Error;
 use std::f64::consts::PI;
 use std::fmt;
 use std::fmt::Display;
+use tracing::{debug, debug_span, warn};
 
 type Result<T> = std::result::Result<T, Fitacf3Error>;
 
@@ -22,6 +26,7 @@ pub enum Fitacf3Error {
     Message(String),
     Lookup(String),
     Mismatch { msg: String },
+    Invalid(ValidationError),
 }
 
 impl Error for Fitacf3Error {}
@@ -32,64 +37,297 @@ impl Display for Fitacf3Error {
             Fitacf3Error::Message(msg) => write!(f, "{}", msg),
             Fitacf3Error::Lookup(msg) => write!(f, "{}", msg),
             Fitacf3Error::Mismatch { msg } => write!(f, "{}", msg),
+            Fitacf3Error::Invalid(err) => write!(f, "Invalid record: {}", err),
         }
     }
 }
 
+impl From<ValidationError> for Fitacf3Error {
+    fn from(err: ValidationError) -> Self {
+        Fitacf3Error::Invalid(err)
+    }
+}
+
+/// Optional stages of the fitting pipeline. The default reproduces the standard FITACF 3.0
+/// algorithm.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FitOptions {
+    /// Reject lags whose fit residuals exceed this many standard deviations, refitting until
+    /// no more lags are rejected. Disabled if `None`.
+    pub clip_sigma: Option<f64>,
+    /// Process the range gates of a record in parallel on the rayon thread pool. This lowers
+    /// the latency of fitting a single record, so is best suited to real-time use.
+    pub parallel_ranges: bool,
+}
+
 pub fn fit_rawacf_record(record: &RawacfRecord, hdw: &HdwInfo) -> Result<FitacfRecord> {
-    let lags = create_lag_list(record);
+    fit_rawacf_record_with_options(record, hdw, &FitOptions::default())
+}
+
+pub fn fit_rawacf_record_with_options(
+    record: &RawacfRecord,
+    hdw: &HdwInfo,
+    options: &FitOptions,
+) -> Result<FitacfRecord> {
+    fit_rawacf_record_in(record, hdw, options, &mut FitWorkspace::default())
+}
+
+/// Fits a record using the buffers held in `workspace`, so that repeated fits reuse the
+/// allocations made by earlier ones.
+pub fn fit_rawacf_record_in(
+    record: &RawacfRecord,
+    hdw: &HdwInfo,
+    options: &FitOptions,
+    workspace: &mut FitWorkspace,
+) -> Result<FitacfRecord> {
+    let _span = debug_span!(
+        "fit_rawacf_record",
+        station = record.station_id,
+        beam = record.beam_num,
+        channel = record.channel
+    )
+    .entered();
+    let noise_power = run_fit_stages(record, options, workspace, &mut |_, _| {})?;
+    debug_span!("determinations")
+        .in_scope(|| determinations(record, &workspace.ranges, noise_power, hdw))
+}
+
+/// Fits any source of ACF data, returning the fitted parameters of each range rather than a
+/// `FitacfRecord`.
+pub fn fit_acf_data(
+    record: &impl AcfData,
+    hdw: &HdwInfo,
+    options: &FitOptions,
+) -> Result<FitResult> {
+    fit_acf_data_in(record, hdw, options, &mut FitWorkspace::default())
+}
+
+/// As `fit_acf_data`, reusing the buffers held in `workspace`.
+pub fn fit_acf_data_in(
+    record: &impl AcfData,
+    hdw: &HdwInfo,
+    options: &FitOptions,
+    workspace: &mut FitWorkspace,
+) -> Result<FitResult> {
+    let _span = debug_span!("fit_acf_data", beam = record.beam_num()).entered();
+    let noise_power = run_fit_stages(record, options, workspace, &mut |_, _| {})?;
+    debug_span!("determinations")
+        .in_scope(|| fit_results(record, &workspace.ranges, noise_power, hdw))
+}
+
+/// Runs the filtering and fitting stages on a record, returning the ranges which survived
+/// filtering along with the noise power used as the ACF cutoff.
+pub fn fit_ranges(record: &impl AcfData, options: &FitOptions) -> Result<(Vec<RangeNode>, f32)> {
+    let mut workspace = FitWorkspace::default();
+    let noise_power = run_fit_stages(record, options, &mut workspace, &mut |_, _| {})?;
+    Ok((workspace.ranges, noise_power))
+}
+
+/// Buffers which are reused from one fit to the next. Each worker thread should own its own
+/// workspace, e.g. through `rayon`'s `map_init`.
+#[derive(Debug, Default)]
+pub struct FitWorkspace {
+    lags: Vec<LagNode>,
+    ranges: Vec<RangeNode>,
+    spare_ranges: Vec<RangeNode>,
+    range_indices: Vec<(usize, usize)>,
+    power_levels: Vec<f32>,
+}
+impl FitWorkspace {
+    pub fn new() -> FitWorkspace {
+        FitWorkspace::default()
+    }
+    /// The ranges which survived the most recent fit.
+    pub fn ranges(&self) -> &[RangeNode] {
+        &self.ranges
+    }
+}
+
+/// The ranges of a record as they were after one stage of the fitting pipeline.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StageSnapshot {
+    pub stage: String,
+    pub ranges: Vec<RangeNode>,
+}
+
+/// The state of the fitting pipeline after every stage, for inspecting intermediate fits.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FitTrace {
+    pub noise_power: f32,
+    pub lags: Vec<LagNode>,
+    pub stages: Vec<StageSnapshot>,
+}
 
-    let noise_power = if record.num_averages <= 0 {
+/// Fits a record, keeping a copy of the ranges after each stage. The final snapshot holds the
+/// same ranges as `fit_ranges` returns.
+pub fn trace_fit_stages(record: &impl AcfData, options: &FitOptions) -> Result<FitTrace> {
+    let mut workspace = FitWorkspace::default();
+    let mut stages = vec![];
+    let noise_power = run_fit_stages(record, options, &mut workspace, &mut |stage, ranges| {
+        stages.push(StageSnapshot {
+            stage: stage.to_string(),
+            ranges: ranges.to_vec(),
+        })
+    })?;
+    Ok(FitTrace {
+        noise_power,
+        lags: workspace.lags,
+        stages,
+    })
+}
+
+/// Runs the fitting stages, leaving the fitted ranges in `workspace.ranges`. Returns the noise
+/// power. `observe` is called with the name of each stage and the ranges after it has run.
+fn run_fit_stages(
+    record: &impl AcfData,
+    options: &FitOptions,
+    workspace: &mut FitWorkspace,
+    observe: &mut dyn FnMut(&'static str, &[RangeNode]),
+) -> Result<f32> {
+    validate(record)?;
+    fill_lag_list(record, &mut workspace.lags);
+
+    let noise_power = if record.num_averages() <= 0 {
+        warn!(
+            beam = record.beam_num(),
+            "Record has no averages, so the noise power is set to 1"
+        );
         1.0
     } else {
-        acf_cutoff_power(record)
+        acf_cutoff_power(record, &mut workspace.power_levels)
     };
-    let mut range_list = vec![];
-    for i in 0..record.range_list.data.len() {
-        let range_num = record.range_list.data[i];
-        if record.lag_zero_power.data[range_num as usize] != 0.0 {
-            range_list.push(RangeNode::new(i, range_num as usize, record, &lags)?)
+    // Pair each range to fit with its index into the acfs
+    workspace.range_indices.clear();
+    for i in 0..record.range_list().len() {
+        let range_num = record.range_list()[i];
+        if record.lag_zero_power()[range_num as usize] != 0.0 {
+            workspace.range_indices.push((i, range_num as usize));
+        }
+    }
+    if workspace.range_indices.is_empty() && !record.range_list().is_empty() {
+        warn!(
+            beam = record.beam_num(),
+            "Every range has zero lag-zero power, so no ranges can be fitted"
+        );
+    }
+    workspace.spare_ranges.append(&mut workspace.ranges);
+    for _ in 0..workspace.range_indices.len() {
+        let range_node = workspace.spare_ranges.pop().unwrap_or_default();
+        workspace.ranges.push(range_node);
+    }
+    let lags = &workspace.lags;
+    let range_list = &mut workspace.ranges;
+    let spare_ranges = &mut workspace.spare_ranges;
+    let parallel = options.parallel_ranges;
+    {
+        let _span = debug_span!("filtering").entered();
+        if parallel {
+            range_list
+                .par_iter_mut()
+                .zip(workspace.range_indices.par_iter())
+                .try_for_each(|(range, &(i, range_num))| range.reset(i, range_num, record, lags))?;
+        } else {
+            range_list
+                .iter_mut()
+                .zip(workspace.range_indices.iter())
+                .try_for_each(|(range, &(i, range_num))| range.reset(i, range_num, record, lags))?;
+        }
+        observe("initial", range_list);
+        filtering::filter_tx_overlapped_lags(record, lags, range_list, parallel);
+        observe("filter_tx_overlapped_lags", range_list);
+        filtering::filter_infinite_lags(range_list, parallel);
+        observe("filter_infinite_lags", range_list);
+        filtering::filter_low_power_lags(record, range_list, parallel);
+        observe("filter_low_power_lags", range_list);
+        filtering::filter_bad_acfs(record, range_list, noise_power, spare_ranges);
+        observe("filter_bad_acfs", range_list);
+    }
+    {
+        let _span = debug_span!("power_fit").entered();
+        fitting::acf_power_fitting(range_list, parallel)?;
+        observe("acf_power_fitting", range_list);
+    }
+    {
+        let _span = debug_span!("phase_unwrap").entered();
+        fitting::calculate_phase_and_elev_sigmas(range_list, record, parallel)?;
+        observe("calculate_phase_and_elev_sigmas", range_list);
+        fitting::acf_phase_unwrap(range_list, parallel);
+        observe("acf_phase_unwrap", range_list);
+    }
+    {
+        let _span = debug_span!("phase_fit").entered();
+        fitting::acf_phase_fitting(range_list, parallel)?;
+        observe("acf_phase_fitting", range_list);
+    }
+    if let Some(clip_sigma) = options.clip_sigma {
+        let _span = debug_span!("outlier_clipping").entered();
+        while filtering::filter_outlier_lags(range_list, clip_sigma) > 0 {
+            observe("filter_outlier_lags", range_list);
+            fitting::acf_power_fitting(range_list, parallel)?;
+            fitting::calculate_phase_and_elev_sigmas(range_list, record, parallel)?;
+            // Removing a lag can change which phase jumps are detected, so unwrap again
+            fitting::acf_phase_unwrap(range_list, parallel);
+            fitting::acf_phase_fitting(range_list, parallel)?;
+            observe("refit_after_clipping", range_list);
         }
     }
-    filtering::filter_tx_overlapped_lags(record, lags, &mut range_list);
-    filtering::filter_infinite_lags(&mut range_list);
-    filtering::filter_low_power_lags(record, &mut range_list);
-    filtering::filter_bad_acfs(record, &mut range_list, noise_power);
-    fitting::acf_power_fitting(&mut range_list)?;
-    fitting::calculate_phase_and_elev_sigmas(&mut range_list, record)?;
-    fitting::acf_phase_unwrap(&mut range_list);
-    fitting::acf_phase_fitting(&mut range_list)?;
-    filtering::filter_bad_fits(&mut range_list)?;
-    fitting::xcf_phase_unwrap(&mut range_list)?;
-    fitting::xcf_phase_fitting(&mut range_list)?;
+    {
+        let _span = debug_span!("xcf_fit").entered();
+        filtering::filter_bad_fits(range_list, spare_ranges)?;
+        observe("filter_bad_fits", range_list);
+        fitting::xcf_phase_unwrap(range_list, parallel)?;
+        observe("xcf_phase_unwrap", range_list);
+        fitting::xcf_phase_fitting(range_list, parallel)?;
+        observe("xcf_phase_fitting", range_list);
+    }
+    debug!(
+        ranges = workspace.range_indices.len(),
+        fitted = range_list.len(),
+        "Fitted record"
+    );
 
-    determinations(record, range_list, noise_power, hdw)
+    Ok(noise_power)
 }
 
 /// Creates the lag table based on the data.
-fn create_lag_list(record: &RawacfRecord) -> Vec<LagNode> {
-    let lag_table = &record.lag_table;
-    let pulse_table = &record.pulse_table;
-    let multi_pulse_increment = record.multi_pulse_increment;
-    let sample_separation = record.sample_separation;
-
+pub fn create_lag_list(record: &impl AcfData) -> Result<Vec<LagNode>> {
+    validate(record)?;
     let mut lags = vec![];
-    for i in 0..record.num_lags as usize {
+    fill_lag_list(record, &mut lags);
+    Ok(lags)
+}
+
+/// Fills `lags` with the lag table of the record, replacing its contents. The record must have
+/// been validated.
+fn fill_lag_list(record: &impl AcfData, lags: &mut Vec<LagNode>) {
+    let lag_table = record.lag_table();
+    let pulse_table = record.pulse_table();
+    let multi_pulse_increment = record.multi_pulse_increment();
+    let sample_separation = record.sample_separation();
+
+    lags.clear();
+    for i in 0..record.num_lags() as usize {
         let mut pulse_1_idx = 0;
         let mut pulse_2_idx = 0;
-        let number = lag_table.data[2 * i + 1] - lag_table.data[2 * i]; // flattened, we want row i, cols 1 and 0
-        for j in 0..record.num_pulses as usize {
-            if lag_table.data[2 * i] == pulse_table.data[j] {
+        let number = lag_table[2 * i + 1] - lag_table[2 * i]; // flattened, we want row i, cols 1 and 0
+        for (j, &pulse) in pulse_table
+            .iter()
+            .enumerate()
+            .take(record.num_pulses() as usize)
+        {
+            if lag_table[2 * i] == pulse {
                 pulse_1_idx = j;
             }
-            if lag_table.data[2 * i + 1] == pulse_table.data[j] {
+            if lag_table[2 * i + 1] == pulse {
                 pulse_2_idx = j;
             }
         }
-        let sample_base_1 =
-            (lag_table.data[2 * i] * (multi_pulse_increment / sample_separation)) as i32;
-        let sample_base_2 =
-            (lag_table.data[2 * i + 1] * (multi_pulse_increment / sample_separation)) as i32;
+        let samples_per_increment = (multi_pulse_increment / sample_separation) as i32;
+        let sample_base_1 = lag_table[2 * i] as i32 * samples_per_increment;
+        let sample_base_2 = lag_table[2 * i + 1] as i32 * samples_per_increment;
         lags.push(LagNode {
             lag_num: number as i32,
             pulses: [pulse_1_idx, pulse_2_idx],
@@ -98,17 +336,17 @@ fn create_lag_list(record: &RawacfRecord) -> Vec<LagNode> {
             sample_base_2,
         });
     }
-    lags
 }
 
 /// Calculates the minimum power value for ACFs in the record (passing)
-fn acf_cutoff_power(rec: &RawacfRecord) -> f32 {
-    let mut sorted_power_levels = rec.lag_zero_power.data.clone();
+fn acf_cutoff_power(rec: &impl AcfData, sorted_power_levels: &mut Vec<f32>) -> f32 {
+    sorted_power_levels.clear();
+    sorted_power_levels.extend_from_slice(rec.lag_zero_power());
     sorted_power_levels.sort_by(|a, b| a.total_cmp(b)); // sort floats
     let mut i: usize = 0;
     let mut j: f64 = 0.0;
     let mut min_power: f64 = 0.0;
-    while j < 10.0 && i < rec.num_ranges as usize / 3 {
+    while j < 10.0 && i < rec.num_ranges() as usize / 3 {
         if sorted_power_levels[i] > 0.0 {
             j += 1.0;
         }
@@ -119,21 +357,23 @@ fn acf_cutoff_power(rec: &RawacfRecord) -> f32 {
         j = 1.0;
     }
     min_power *= cutoff_power_correction(rec) / j;
-    if min_power < ACF_SNR_CUTOFF && rec.search_noise > 0.0 {
-        min_power = rec.search_noise as f64;
+    if min_power < ACF_SNR_CUTOFF && rec.search_noise() > 0.0 {
+        min_power = rec.search_noise() as f64;
     }
     min_power as f32
 }
 
 /// Passing
-fn cutoff_power_correction(rec: &RawacfRecord) -> f64 {
-    let std_dev = 1.0 / (rec.num_averages as f64).sqrt();
+fn cutoff_power_correction(rec: &impl AcfData) -> f64 {
+    let std_dev = 1.0 / (rec.num_averages() as f64).sqrt();
 
     let mut i = 0.0;
     let mut cumulative_pdf = 0.0;
     let mut cumulative_pdf_x_norm_power = 0.0;
     let mut normalized_power;
-    while cumulative_pdf < (10.0 / rec.num_ranges as f64) {
+    // Records of 10 ranges or fewer never reach the cutoff, so stop once the PDF is negligible
+    let max_normalized_power = 1.0 + 10.0 * std_dev;
+    while cumulative_pdf < (10.0 / rec.num_ranges() as f64) && i / 1000.0 <= max_normalized_power {
         // Normalized power for calculating model PDF (Gaussian)
         normalized_power = i / 1000.0;
         let x = -(normalized_power - 1.0) * (normalized_power - 1.0) / (2.0 * std_dev * std_dev);
@@ -1,8 +1,10 @@
+use crate::fitting::acf_data::AcfData;
 use crate::fitting::fitacf3::fitacf_v3::Fitacf3Error;
-use dmap::formats::RawacfRecord;
+use std::fmt;
 use std::iter::zip;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RangeNode {
     pub range_num: usize,
     pub range_idx: usize,
@@ -19,99 +21,167 @@ pub struct RangeNode {
     pub quad_pwr_fit_err: Option<FittedData>,
     pub phase_fit: Option<FittedData>,
     pub elev_fit: Option<FittedData>,
+    pub num_clipped_lags: usize,
+    pub power_mask: Vec<Option<LagRejection>>,
+    pub phase_mask: Vec<Option<LagRejection>>,
 }
 impl RangeNode {
     pub fn new(
         index: usize,
         range_num: usize,
-        record: &RawacfRecord,
+        record: &impl AcfData,
         lags: &[LagNode],
     ) -> Result<RangeNode, Fitacf3Error> {
-        let cross_range_interference =
-            RangeNode::calculate_cross_range_interference(range_num, record);
-        let alpha_2 =
-            RangeNode::calculate_alphas(range_num, &cross_range_interference, record, lags);
-        let phases = PhaseNode::new(record, "acfd", lags, index)?;
-        let elevations = PhaseNode::new(record, "xcfd", lags, index)?;
-        let powers = PowerNode::new(record, lags, index, range_num, &alpha_2);
-        Ok(RangeNode {
-            range_idx: index,
+        let mut range_node = RangeNode::default();
+        range_node.reset(index, range_num, record, lags)?;
+        Ok(range_node)
+    }
+    /// Reinitializes the node for a new range, reusing the allocations it already holds.
+    pub fn reset(
+        &mut self,
+        index: usize,
+        range_num: usize,
+        record: &impl AcfData,
+        lags: &[LagNode],
+    ) -> Result<(), Fitacf3Error> {
+        self.range_idx = index;
+        self.range_num = range_num;
+        self.refractive_idx = 1.0;
+        RangeNode::calculate_cross_range_interference(
+            range_num,
+            record,
+            &mut self.cross_range_interference,
+        );
+        RangeNode::calculate_alphas(
             range_num,
-            cross_range_interference,
-            refractive_idx: 1.0,
-            power_alpha_2: alpha_2.clone(),
-            phase_alpha_2: alpha_2,
-            phases,
-            powers,
-            elev: elevations,
-            lin_pwr_fit: None,
-            quad_pwr_fit: None,
-            lin_pwr_fit_err: None,
-            quad_pwr_fit_err: None,
-            phase_fit: None,
-            elev_fit: None,
-        })
+            &self.cross_range_interference,
+            record,
+            lags,
+            &mut self.power_alpha_2,
+        );
+        self.phase_alpha_2.clear();
+        self.phase_alpha_2.extend_from_slice(&self.power_alpha_2);
+        self.phases.reset(record, "acfd", lags, index)?;
+        self.elev.reset(record, "xcfd", lags, index)?;
+        self.powers
+            .reset(record, lags, index, range_num, &self.power_alpha_2);
+        self.lin_pwr_fit = None;
+        self.quad_pwr_fit = None;
+        self.lin_pwr_fit_err = None;
+        self.quad_pwr_fit_err = None;
+        self.phase_fit = None;
+        self.elev_fit = None;
+        self.num_clipped_lags = 0;
+        self.power_mask.clear();
+        self.power_mask.resize(lags.len(), None);
+        self.phase_mask.clear();
+        self.phase_mask.resize(lags.len(), None);
+        Ok(())
     }
-    fn calculate_cross_range_interference(range_num: usize, rec: &RawacfRecord) -> Vec<f64> {
-        let tau: i16 = if rec.sample_separation != 0 {
-            rec.multi_pulse_increment / rec.sample_separation
-        } else {
-            // TODO: Log warning?
-            rec.multi_pulse_increment / rec.tx_pulse_length
-        };
+    /// Removes the flagged lags from the power fit in a single pass, recording why in the
+    /// power mask. `rejected` has one entry per lag currently in the power fit.
+    pub fn reject_power_lags(&mut self, rejected: &[bool], reason: LagRejection) {
+        for (&lag_idx, _) in zip(&self.powers.lag_idx, rejected).filter(|(_, &r)| r) {
+            self.power_mask[lag_idx] = Some(reason);
+        }
+        self.powers.retain(rejected);
+        retain_unrejected(&mut self.power_alpha_2, rejected);
+    }
+    /// Removes the flagged lags from the ACF and XCF phase fits in a single pass, recording why
+    /// in the phase mask. `rejected` has one entry per lag currently in the phase fits.
+    pub fn reject_phase_lags(&mut self, rejected: &[bool], reason: LagRejection) {
+        for (&lag_idx, _) in zip(&self.phases.lag_idx, rejected).filter(|(_, &r)| r) {
+            self.phase_mask[lag_idx] = Some(reason);
+        }
+        self.phases.retain(rejected);
+        self.elev.retain(rejected);
+        retain_unrejected(&mut self.phase_alpha_2, rejected);
+    }
+    fn calculate_cross_range_interference(
+        range_num: usize,
+        rec: &impl AcfData,
+        interference_for_pulses: &mut Vec<f64>,
+    ) {
+        // Validated records have a positive sample separation
+        let tau = rec
+            .multi_pulse_increment()
+            .checked_div(rec.sample_separation())
+            .or_else(|| {
+                rec.multi_pulse_increment()
+                    .checked_div(rec.tx_pulse_length())
+            })
+            .unwrap_or(0) as i32;
 
-        let mut interference_for_pulses: Vec<f64> = vec![];
-        for pulse_to_check in 0..rec.num_pulses as usize {
+        interference_for_pulses.clear();
+        for pulse_to_check in 0..rec.num_pulses() as usize {
             let mut total_interference: f64 = 0.0;
-            for pulse in 0..rec.num_pulses as usize {
-                let pulse_diff = rec.pulse_table.data[pulse_to_check] - rec.pulse_table.data[pulse];
-                let range_to_check = (pulse_diff * tau + range_num as i16) as usize;
-                if (pulse != pulse_to_check) && (range_to_check < rec.num_ranges as usize) {
-                    total_interference += rec.lag_zero_power.data[range_to_check] as f64;
+            for pulse in 0..rec.num_pulses() as usize {
+                let pulse_diff =
+                    rec.pulse_table()[pulse_to_check] as i32 - rec.pulse_table()[pulse] as i32;
+                // Ranges before the first are out of the record, like those past the last
+                let range_to_check = usize::try_from(pulse_diff * tau + range_num as i32);
+                match range_to_check {
+                    Ok(r) if pulse != pulse_to_check && r < rec.num_ranges() as usize => {
+                        total_interference += rec.lag_zero_power()[r] as f64;
+                    }
+                    _ => {}
                 }
             }
             interference_for_pulses.push(total_interference);
         }
-        interference_for_pulses
     }
     fn calculate_alphas(
         range_num: usize,
         cross_range_interference: &[f64],
-        rec: &RawacfRecord,
+        rec: &impl AcfData,
         lags: &[LagNode],
-    ) -> Vec<f64> {
-        let mut alpha_2: Vec<f64> = vec![];
+        alpha_2: &mut Vec<f64>,
+    ) {
+        alpha_2.clear();
         for lag in lags.iter() {
-            let pulse_1_interference = cross_range_interference[lag.pulses[0] as usize];
-            let pulse_2_interference = cross_range_interference[lag.pulses[1] as usize];
-            let lag_zero_power = rec.lag_zero_power.data[range_num] as f64;
+            let pulse_1_interference = cross_range_interference[lag.pulses[0]];
+            let pulse_2_interference = cross_range_interference[lag.pulses[1]];
+            let lag_zero_power = rec.lag_zero_power()[range_num] as f64;
             alpha_2.push(
                 lag_zero_power * lag_zero_power
                     / ((lag_zero_power + pulse_1_interference)
                         * (lag_zero_power + pulse_2_interference)),
             );
         }
-        alpha_2
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PhaseNode {
     pub phases: Vec<f64>,
     pub t: Vec<f64>,
     pub std_dev: Vec<f64>,
+    pub lag_idx: Vec<usize>,
 }
 impl PhaseNode {
     pub fn new(
-        rec: &RawacfRecord,
+        rec: &impl AcfData,
         phase_type: &str,
         lags: &[LagNode],
         range_idx: usize,
     ) -> Result<PhaseNode, Fitacf3Error> {
+        let mut phase_node = PhaseNode::default();
+        phase_node.reset(rec, phase_type, lags, range_idx)?;
+        Ok(phase_node)
+    }
+    /// Refills the node for a new range, reusing the allocations it already holds.
+    pub fn reset(
+        &mut self,
+        rec: &impl AcfData,
+        phase_type: &str,
+        lags: &[LagNode],
+        range_idx: usize,
+    ) -> Result<(), Fitacf3Error> {
         let acfd = match phase_type {
-            "acfd" => &rec.acfs.data,
-            "xcfd" => match &rec.xcfs {
-                Some(x) => &x.data,
+            "acfd" => rec.acfs(),
+            "xcfd" => match rec.xcfs() {
+                Some(x) => x,
                 None => Err(Fitacf3Error::Message(
                     "Cannot find xcfs in data".to_string(),
                 ))?,
@@ -121,77 +191,149 @@ impl PhaseNode {
                 phase_type
             )))?,
         };
-        let start_idx = range_idx * 2 * rec.num_lags as usize;
-        let end_idx = start_idx + 2 * rec.num_lags as usize;
-        let phases = acfd[start_idx..end_idx]
-            .chunks_exact(2)
-            .map(|x| (x[1] as f64).atan2(x[0] as f64))
-            .collect();
-        let t = lags
-            .iter()
-            .map(|x| (x.lag_num * rec.multi_pulse_increment as i32) as f64 * 1.0e-6)
-            .collect();
-        let std_dev = (0..rec.num_lags).map(|_| 0.0).collect();
-        Ok(PhaseNode { phases, t, std_dev })
+        let start_idx = range_idx * 2 * rec.num_lags() as usize;
+        let end_idx = start_idx + 2 * rec.num_lags() as usize;
+        self.phases.clear();
+        self.phases.extend(
+            acfd[start_idx..end_idx]
+                .chunks_exact(2)
+                .map(|x| (x[1] as f64).atan2(x[0] as f64)),
+        );
+        self.t.clear();
+        self.t.extend(
+            lags.iter()
+                .map(|x| (x.lag_num * rec.multi_pulse_increment() as i32) as f64 * 1.0e-6),
+        );
+        self.std_dev.clear();
+        self.std_dev.resize(rec.num_lags() as usize, 0.0);
+        self.lag_idx.clear();
+        self.lag_idx.extend(0..lags.len());
+        Ok(())
     }
-    pub fn remove(&mut self, idx: usize) {
-        self.phases.remove(idx);
-        self.t.remove(idx);
-        self.std_dev.remove(idx);
+    /// Compacts the node in place, dropping the entries flagged in `rejected`.
+    pub fn retain(&mut self, rejected: &[bool]) {
+        retain_unrejected(&mut self.phases, rejected);
+        retain_unrejected(&mut self.t, rejected);
+        retain_unrejected(&mut self.std_dev, rejected);
+        retain_unrejected(&mut self.lag_idx, rejected);
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PowerNode {
     pub ln_power: Vec<f64>,
     pub t: Vec<f64>,
     pub std_dev: Vec<f64>,
+    pub lag_idx: Vec<usize>,
 }
 impl PowerNode {
     pub fn new(
-        rec: &RawacfRecord,
+        rec: &impl AcfData,
         lags: &[LagNode],
         range_idx: usize,
         range_num: usize,
         alpha_2: &[f64],
     ) -> PowerNode {
-        let pwr_0 = rec.lag_zero_power.data[range_num] as f64;
+        let mut power_node = PowerNode::default();
+        power_node.reset(rec, lags, range_idx, range_num, alpha_2);
+        power_node
+    }
+    /// Refills the node for a new range, reusing the allocations it already holds.
+    pub fn reset(
+        &mut self,
+        rec: &impl AcfData,
+        lags: &[LagNode],
+        range_idx: usize,
+        range_num: usize,
+        alpha_2: &[f64],
+    ) {
+        let pwr_0 = rec.lag_zero_power()[range_num] as f64;
         // acfs stores as [num_ranges, num_lags, 2] in memory, with 2 corresponding to real, imag
-        let start_idx = range_idx * 2 * rec.num_lags as usize;
-        let end_idx = start_idx + 2 * rec.num_lags as usize;
-        let powers: Vec<f64> = rec.acfs.data[start_idx..end_idx]
-            .chunks_exact(2)
-            .map(|x| {
-                let real = x[0] as f64;
-                let imag = x[1] as f64;
-                (real * real + imag * imag).sqrt()
-            })
-            .collect();
-        let normalized_power: Vec<f64> = powers.iter().map(|x| x * x / (pwr_0 * pwr_0)).collect();
+        let start_idx = range_idx * 2 * rec.num_lags() as usize;
+        let end_idx = start_idx + 2 * rec.num_lags() as usize;
+        self.ln_power.clear();
+        self.std_dev.clear();
+        for (x, alpha) in zip(rec.acfs()[start_idx..end_idx].chunks_exact(2), alpha_2) {
+            let real = x[0] as f64;
+            let imag = x[1] as f64;
+            let power = (real * real + imag * imag).sqrt();
+            let pwr_norm = power * power / (pwr_0 * pwr_0);
+            self.ln_power.push(power.ln());
+            self.std_dev.push(
+                pwr_0 * ((pwr_norm + 1.0 / alpha) / (2.0 * rec.num_averages() as f64)).sqrt(),
+            );
+        }
+        self.t.clear();
+        self.t.extend(
+            lags.iter()
+                .map(|x| (x.lag_num * rec.multi_pulse_increment() as i32) as f64 * 1.0e-6),
+        );
+        self.lag_idx.clear();
+        self.lag_idx.extend(0..lags.len());
+    }
+    /// Compacts the node in place, dropping the entries flagged in `rejected`.
+    pub fn retain(&mut self, rejected: &[bool]) {
+        retain_unrejected(&mut self.ln_power, rejected);
+        retain_unrejected(&mut self.t, rejected);
+        retain_unrejected(&mut self.std_dev, rejected);
+        retain_unrejected(&mut self.lag_idx, rejected);
+    }
+}
 
-        let sigmas: Vec<f64> = zip(normalized_power.iter(), alpha_2.iter())
-            .map(|(pwr_norm, alpha)| {
-                pwr_0 * ((pwr_norm + 1.0 / alpha) / (2.0 * rec.num_averages as f64)).sqrt()
-            })
-            .collect();
-        let t = lags
-            .iter()
-            .map(|x| (x.lag_num * rec.multi_pulse_increment as i32) as f64 * 1.0e-6)
-            .collect();
-        PowerNode {
-            ln_power: powers.iter().map(|x| x.ln()).collect(),
-            t,
-            std_dev: sigmas,
+/// Removes the elements of `values` flagged in `rejected` in a single in-place pass, keeping the
+/// order of the remaining elements.
+pub fn retain_unrejected<T>(values: &mut Vec<T>, rejected: &[bool]) {
+    let mut flags = rejected.iter();
+    values.retain(|_| !flags.next().copied().unwrap_or(false));
+}
+
+/// Moves the elements of `values` flagged in `rejected` onto the end of `spare`, keeping the
+/// order of the remaining elements, so that the allocations they hold can be reused.
+pub fn move_rejected<T>(values: &mut Vec<T>, rejected: &[bool], spare: &mut Vec<T>) {
+    let mut kept = 0;
+    for idx in 0..values.len() {
+        if !rejected.get(idx).copied().unwrap_or(false) {
+            values.swap(kept, idx);
+            kept += 1;
         }
     }
-    pub fn remove(&mut self, idx: usize) {
-        self.ln_power.remove(idx);
-        self.t.remove(idx);
-        self.std_dev.remove(idx);
+    spare.extend(values.drain(kept..));
+}
+
+/// Reason a lag was removed from a fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LagRejection {
+    TxOverlap,
+    NonFinite,
+    LowPower,
+    Clipped,
+}
+impl LagRejection {
+    /// Numeric code of the reason, with 0 reserved for lags which were kept.
+    pub fn code(&self) -> u8 {
+        match self {
+            LagRejection::TxOverlap => 1,
+            LagRejection::NonFinite => 2,
+            LagRejection::LowPower => 3,
+            LagRejection::Clipped => 4,
+        }
+    }
+}
+impl fmt::Display for LagRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LagRejection::TxOverlap => write!(f, "tx_overlap"),
+            LagRejection::NonFinite => write!(f, "non_finite"),
+            LagRejection::LowPower => write!(f, "low_power"),
+            LagRejection::Clipped => write!(f, "clipped"),
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LagNode {
     pub lag_num: i32,
     pub pulses: [usize; 2],
@@ -200,7 +342,8 @@ pub struct LagNode {
     pub sample_base_2: i32,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FittedData {
     pub delta: f64,
     pub intercept: f64,
@@ -216,6 +359,7 @@ pub struct FittedData {
 }
 
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sums {
     pub sum: f64,
     pub sum_x: f64,
@@ -224,6 +368,8 @@ pub struct Sums {
     pub sum_xy: f64,
 }
 
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FitType {
     Linear,
     Quadratic,
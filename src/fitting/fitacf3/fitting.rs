@@ -1,17 +1,43 @@
+use crate::fitting::acf_data::AcfData;
 use crate::fitting::fitacf3::fitacf_v3::Fitacf3Error;
 use crate::fitting::fitacf3::fitstruct::{FitType, RangeNode};
 use crate::fitting::fitacf3::least_squares::LeastSquares;
-use dmap::formats::RawacfRecord;
+use rayon::prelude::*;
 use std::f64::consts::PI;
 use std::iter::zip;
 
 type Result<T> = std::result::Result<T, Fitacf3Error>;
 
+/// Applies `f` to every range, spreading the ranges over the rayon thread pool if `parallel`.
+pub fn for_each_range<F>(ranges: &mut [RangeNode], parallel: bool, f: F)
+where
+    F: Fn(&mut RangeNode) + Sync + Send,
+{
+    if parallel {
+        ranges.par_iter_mut().for_each(f)
+    } else {
+        ranges.iter_mut().for_each(f)
+    }
+}
+
+/// Applies the fallible `f` to every range, spreading the ranges over the rayon thread pool if
+/// `parallel`. Stops at the first error.
+pub fn try_for_each_range<F>(ranges: &mut [RangeNode], parallel: bool, f: F) -> Result<()>
+where
+    F: Fn(&mut RangeNode) -> Result<()> + Sync + Send,
+{
+    if parallel {
+        ranges.par_iter_mut().try_for_each(f)
+    } else {
+        ranges.iter_mut().try_for_each(f)
+    }
+}
+
 /// passing
-pub fn acf_power_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
+pub fn acf_power_fitting(ranges: &mut [RangeNode], parallel: bool) -> Result<()> {
     let lsq = LeastSquares::new(1, 1);
 
-    for mut range in ranges {
+    try_for_each_range(ranges, parallel, |range| {
         let log_powers = &range.powers.ln_power;
         let sigmas = &range.powers.std_dev;
         let t = &range.powers.t;
@@ -38,14 +64,14 @@ pub fn acf_power_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
             &log_corrected_sigmas,
             FitType::Quadratic,
         ));
-    }
-    Ok(())
+        Ok(())
+    })
 }
 
 /// passing
-pub fn acf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
+pub fn acf_phase_fitting(ranges: &mut [RangeNode], parallel: bool) -> Result<()> {
     let lsq = LeastSquares::new(1, 1);
-    for mut range in ranges {
+    try_for_each_range(ranges, parallel, |range| {
         let phases = &range.phases.phases;
         let sigmas = &range.phases.std_dev;
         let t = &range.phases.t;
@@ -57,14 +83,14 @@ pub fn acf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
             ))?
         }
         range.phase_fit = Some(lsq.one_parameter_line_fit(t, phases, sigmas));
-    }
-    Ok(())
+        Ok(())
+    })
 }
 
 /// passing
-pub fn xcf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
+pub fn xcf_phase_fitting(ranges: &mut [RangeNode], parallel: bool) -> Result<()> {
     let lsq = LeastSquares::new(1, 1);
-    for mut range in ranges {
+    try_for_each_range(ranges, parallel, |range| {
         let phases = &range.elev.phases;
         let sigmas = &range.elev.std_dev;
         let t = &range.elev.t;
@@ -76,56 +102,67 @@ pub fn xcf_phase_fitting(ranges: &mut Vec<RangeNode>) -> Result<()> {
             ))?
         }
         range.elev_fit = Some(lsq.two_parameter_line_fit(t, phases, sigmas, FitType::Linear));
-    }
-    Ok(())
+        Ok(())
+    })
 }
 
 /// passing
 pub fn calculate_phase_and_elev_sigmas(
-    ranges: &mut Vec<RangeNode>,
-    rec: &RawacfRecord,
+    ranges: &mut [RangeNode],
+    rec: &impl AcfData,
+    parallel: bool,
 ) -> Result<()> {
-    for mut range in ranges {
-        let inverse_alpha_2: Vec<f64> = range.phase_alpha_2.iter().map(|x| 1.0 / x).collect();
-        // let elevs_inverse_alpha_2: Vec<f64> = range.alpha_2.iter().map(|x| 1.0 / x).collect();
-        let pwr_values: Vec<f64> = range
-            .phases
-            .t
-            .iter()
-            .map(|t| (-1.0 * range.lin_pwr_fit.as_ref().unwrap().slope.abs() * t).exp())
-            .collect();
-        let inverse_pwr_squared: Vec<f64> = pwr_values.iter().map(|x| 1.0 / (x * x)).collect();
-        let phase_numerator: Vec<f64> = zip(inverse_alpha_2.iter(), inverse_pwr_squared.iter())
-            .map(|(x, y)| x * y - 1.0)
-            .collect();
-        let denominator = 2.0 * rec.num_averages as f64;
-        let mut phase_sigmas: Vec<f64> = phase_numerator
-            .iter()
-            .map(|x| (x / denominator).sqrt())
-            .collect();
-        if phase_sigmas.iter().filter(|&x| !x.is_finite()).count() > 0 {
+    let denominator = 2.0 * rec.num_averages() as f64;
+    try_for_each_range(ranges, parallel, |range| {
+        let power_slope = range
+            .lin_pwr_fit
+            .as_ref()
+            .ok_or_else(|| {
+                Fitacf3Error::Message(
+                    "Cannot calculate phase sigmas since power not linearly fit".to_string(),
+                )
+            })?
+            .slope
+            .abs();
+        range.phases.std_dev.clear();
+        for (alpha_2, t) in zip(range.phase_alpha_2.iter(), range.phases.t.iter()) {
+            let inverse_alpha_2 = 1.0 / alpha_2;
+            let pwr_value = (-power_slope * t).exp();
+            let inverse_pwr_squared = 1.0 / (pwr_value * pwr_value);
+            let phase_numerator = inverse_alpha_2 * inverse_pwr_squared - 1.0;
+            range
+                .phases
+                .std_dev
+                .push((phase_numerator / denominator).sqrt());
+        }
+        if range.phases.std_dev.iter().any(|x| !x.is_finite()) {
             Err(Fitacf3Error::Message(format!(
                 "Phase sigmas bad at range {}",
                 range.range_idx
             )))?
         }
-        range.phases.std_dev = phase_sigmas.clone();
+        range.elev.std_dev.clear();
+        range.elev.std_dev.extend_from_slice(&range.phases.std_dev);
         // Since lag 0 phase is included for elevation fit, set lag 0 sigma the same as lag 1 sigma
-        phase_sigmas[0] = phase_sigmas[1];
-        range.elev.std_dev = phase_sigmas; // = elev_sigmas;
-    }
-    Ok(())
+        if range.elev.std_dev.len() > 1 {
+            range.elev.std_dev[0] = range.elev.std_dev[1];
+        }
+        Ok(())
+    })
 }
 
 /// passing
-pub fn acf_phase_unwrap(ranges: &mut Vec<RangeNode>) {
-    for mut range in ranges {
+pub fn acf_phase_unwrap(ranges: &mut [RangeNode], parallel: bool) {
+    for_each_range(ranges, parallel, |range| {
         let (mut slope_numerator, mut slope_denominator) = (0.0, 0.0);
 
         let phases = &range.phases.phases;
         let sigmas = &range.phases.std_dev;
         let t = &range.phases.t;
 
+        if phases.is_empty() || sigmas.is_empty() || t.is_empty() {
+            return;
+        }
         // This is to skip the first element
         let mut phase_prev = phases[0];
         let mut sigma_prev = sigmas[0];
@@ -186,12 +223,12 @@ pub fn acf_phase_unwrap(ranges: &mut Vec<RangeNode>) {
                 range.phases.phases = new_phases;
             }
         }
-    }
+    });
 }
 
 /// passing
-pub fn xcf_phase_unwrap(ranges: &mut Vec<RangeNode>) -> Result<()> {
-    for mut range in ranges {
+pub fn xcf_phase_unwrap(ranges: &mut [RangeNode], parallel: bool) -> Result<()> {
+    try_for_each_range(ranges, parallel, |range| {
         let (mut sum_xy, mut sum_xx) = (0.0, 0.0);
 
         let phases = &range.elev.phases;
@@ -215,8 +252,8 @@ pub fn xcf_phase_unwrap(ranges: &mut Vec<RangeNode>) -> Result<()> {
                 range.elev.phases = new_phases;
             }
         }
-    }
-    Ok(())
+        Ok(())
+    })
 }
 
 /// passing
@@ -230,6 +267,10 @@ fn phase_correction(slope_estimate: f64, phases: &[f64], times: &[f64]) -> (Vec<
     let corrected_phase: Vec<f64> = zip(phases.iter(), phase_diff.iter())
         .map(|(p, &corr)| p + corr as f64 * 2.0 * PI)
         .collect();
-    let total_corrections: i32 = phase_diff.iter().map(|x| x.abs()).sum();
+    // Non-finite slopes saturate the corrections, which must not overflow the total
+    let total_corrections: i32 = phase_diff
+        .iter()
+        .map(|x| x.saturating_abs())
+        .fold(0, i32::saturating_add);
     (corrected_phase, total_corrections)
 }
@@ -66,38 +66,36 @@ impl LeastSquares {
     }
     /// passing
     fn find_sums(x_vals: &[f64], y_vals: &[f64], sigmas: &[f64], fit_type: &FitType) -> Sums {
-        let nonzero_sigma: Vec<usize> = sigmas
+        let sum: f64 = sigmas
             .iter()
-            .enumerate()
-            .filter_map(|(i, &x)| if x != 0.0 { Some(i) } else { None })
-            .collect();
-        let sigma_squared: Vec<f64> = nonzero_sigma
-            .iter()
-            .map(|&x| sigmas[x] * sigmas[x])
-            .collect();
-
-        let sum: f64 = sigma_squared.iter().map(|x| 1.0 / x).sum();
+            .filter(|&&x| x != 0.0)
+            .map(|x| 1.0 / (x * x))
+            .sum();
         let mut sum_x: f64 = 0.0;
         let mut sum_y: f64 = 0.0;
         let mut sum_xx: f64 = 0.0;
         let mut sum_xy: f64 = 0.0;
 
+        let nonzero_sigma = sigmas
+            .iter()
+            .enumerate()
+            .filter(|(_, &x)| x != 0.0)
+            .map(|(i, &x)| (i, x * x));
         match fit_type {
             FitType::Linear => {
-                for (new, &orig) in nonzero_sigma.iter().enumerate() {
-                    sum_x += x_vals[orig] / sigma_squared[new];
-                    sum_y += y_vals[orig] / sigma_squared[new];
-                    sum_xx += x_vals[orig] * x_vals[orig] / sigma_squared[new];
-                    sum_xy += x_vals[orig] * y_vals[orig] / sigma_squared[new];
+                for (i, sigma_squared) in nonzero_sigma {
+                    sum_x += x_vals[i] / sigma_squared;
+                    sum_y += y_vals[i] / sigma_squared;
+                    sum_xx += x_vals[i] * x_vals[i] / sigma_squared;
+                    sum_xy += x_vals[i] * y_vals[i] / sigma_squared;
                 }
             }
             FitType::Quadratic => {
-                for (new, &orig) in nonzero_sigma.iter().enumerate() {
-                    sum_x += x_vals[orig] * x_vals[orig] / sigma_squared[new];
-                    sum_y += y_vals[orig] / sigma_squared[new];
-                    sum_xx += x_vals[orig] * x_vals[orig] * x_vals[orig] * x_vals[orig]
-                        / sigma_squared[new];
-                    sum_xy += x_vals[orig] * x_vals[orig] * y_vals[orig] / sigma_squared[new];
+                for (i, sigma_squared) in nonzero_sigma {
+                    sum_x += x_vals[i] * x_vals[i] / sigma_squared;
+                    sum_y += y_vals[i] / sigma_squared;
+                    sum_xx += x_vals[i] * x_vals[i] * x_vals[i] * x_vals[i] / sigma_squared;
+                    sum_xy += x_vals[i] * x_vals[i] * y_vals[i] / sigma_squared;
                 }
             }
         }
@@ -116,30 +114,27 @@ impl LeastSquares {
         sigmas: &[f64],
         fit_type: &FitType,
     ) -> f64 {
-        let nonzero_sigma: Vec<usize> = sigmas
-            .iter()
-            .enumerate()
-            .filter_map(|(i, &x)| if x != 0.0 { Some(i) } else { None })
-            .collect();
-        let mut chi: Vec<f64> = vec![];
+        let nonzero_sigma =
+            sigmas
+                .iter()
+                .enumerate()
+                .filter_map(|(i, &x)| if x != 0.0 { Some(i) } else { None });
         match fit_type {
-            FitType::Linear => {
-                for &i in nonzero_sigma.iter() {
-                    chi.push(
-                        ((y_vals[i] - fitted.intercept) - (fitted.slope * x_vals[i])) / sigmas[i],
-                    );
-                }
-                chi.iter().map(|x| x * x).sum()
-            }
-            FitType::Quadratic => {
-                for &i in nonzero_sigma.iter() {
-                    chi.push(
-                        ((y_vals[i] - fitted.intercept) - (fitted.slope * x_vals[i] * x_vals[i]))
-                            / sigmas[i],
-                    );
-                }
-                chi.iter().map(|x| x * x).sum()
-            }
+            FitType::Linear => nonzero_sigma
+                .map(|i| {
+                    let chi =
+                        ((y_vals[i] - fitted.intercept) - (fitted.slope * x_vals[i])) / sigmas[i];
+                    chi * chi
+                })
+                .sum(),
+            FitType::Quadratic => nonzero_sigma
+                .map(|i| {
+                    let chi = ((y_vals[i] - fitted.intercept)
+                        - (fitted.slope * x_vals[i] * x_vals[i]))
+                        / sigmas[i];
+                    chi * chi
+                })
+                .sum(),
         }
     }
 }
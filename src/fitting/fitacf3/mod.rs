@@ -4,3 +4,5 @@ pub mod fitacf_v3;
 pub mod fitstruct;
 pub mod fitting;
 pub mod least_squares;
+pub mod model;
+pub mod stream;
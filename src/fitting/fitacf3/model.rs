@@ -0,0 +1,95 @@
+use crate::fitting::acf_data::AcfData;
+use crate::fitting::fitacf3::fitacf_v3::{create_lag_list, fit_ranges, FitOptions, Fitacf3Error};
+use crate::fitting::fitacf3::fitstruct::{FitType, LagNode, RangeNode};
+
+type Result<T> = std::result::Result<T, Fitacf3Error>;
+
+/// The measured ACF of a single range alongside the ACF rebuilt from the fit results.
+/// Complex values are stored as `[real, imag]`, with one entry per lag in the lag table.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModelAcf {
+    pub range_num: usize,
+    pub range_idx: usize,
+    pub lag_nums: Vec<i32>,
+    pub t: Vec<f64>,
+    pub measured: Vec<[f64; 2]>,
+    pub model: Vec<[f64; 2]>,
+    pub residuals: Vec<[f64; 2]>,
+}
+impl ModelAcf {
+    /// Rebuilds the model ACF of a fitted range. The power follows the linear (lambda) or
+    /// quadratic (sigma) power fit, and the phase follows the ACF phase fit.
+    pub fn new(
+        range: &RangeNode,
+        rec: &impl AcfData,
+        lags: &[LagNode],
+        fit_type: FitType,
+    ) -> Result<ModelAcf> {
+        let power_fit = match fit_type {
+            FitType::Linear => range.lin_pwr_fit.as_ref(),
+            FitType::Quadratic => range.quad_pwr_fit.as_ref(),
+        }
+        .ok_or_else(|| Fitacf3Error::Message("Cannot model ACF since power not fit".to_string()))?;
+        let phase_fit = range.phase_fit.as_ref().ok_or_else(|| {
+            Fitacf3Error::Message("Cannot model ACF since phase not fit".to_string())
+        })?;
+
+        let start_idx = range.range_idx * 2 * rec.num_lags() as usize;
+        let end_idx = start_idx + 2 * rec.num_lags() as usize;
+        if end_idx > rec.acfs().len() || lags.len() != rec.num_lags() as usize {
+            Err(Fitacf3Error::Mismatch {
+                msg: format!("Cannot model ACF at range {}", range.range_num),
+            })?
+        }
+        let measured: Vec<[f64; 2]> = rec.acfs()[start_idx..end_idx]
+            .chunks_exact(2)
+            .map(|x| [x[0] as f64, x[1] as f64])
+            .collect();
+        let t: Vec<f64> = lags
+            .iter()
+            .map(|x| (x.lag_num * rec.multi_pulse_increment() as i32) as f64 * 1.0e-6)
+            .collect();
+        let model: Vec<[f64; 2]> = t
+            .iter()
+            .map(|&t| {
+                let ln_power = match fit_type {
+                    FitType::Linear => power_fit.intercept + power_fit.slope * t,
+                    FitType::Quadratic => power_fit.intercept + power_fit.slope * t * t,
+                };
+                let power = ln_power.exp();
+                let phase = phase_fit.slope * t;
+                [power * phase.cos(), power * phase.sin()]
+            })
+            .collect();
+        let residuals = measured
+            .iter()
+            .zip(model.iter())
+            .map(|(m, f)| [m[0] - f[0], m[1] - f[1]])
+            .collect();
+
+        Ok(ModelAcf {
+            range_num: range.range_num,
+            range_idx: range.range_idx,
+            lag_nums: lags.iter().map(|x| x.lag_num).collect(),
+            t,
+            measured,
+            model,
+            residuals,
+        })
+    }
+}
+
+/// Fits a record and rebuilds the model ACF for every range which survived fitting.
+pub fn model_acfs(
+    rec: &impl AcfData,
+    fit_type: FitType,
+    options: &FitOptions,
+) -> Result<Vec<ModelAcf>> {
+    let (ranges, _) = fit_ranges(rec, options)?;
+    let lags = create_lag_list(rec)?;
+    ranges
+        .iter()
+        .map(|range| ModelAcf::new(range, rec, &lags, fit_type))
+        .collect()
+}
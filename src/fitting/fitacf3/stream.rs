@@ -0,0 +1,132 @@
+use crate::error::BackscatterError;
+use crate::fitting::fitacf3::fitacf_v3::{fit_rawacf_record_in, FitOptions, FitWorkspace};
+use crate::utils::dmap_stream::{RecordReader, RecordWriter};
+use crate::utils::hdw::{record_datetime, HdwInfo, HdwProvider};
+use dmap::formats::{FitacfRecord, RawacfRecord};
+use rayon::prelude::*;
+use std::io::{Read, Write};
+use std::iter::zip;
+use tracing::warn;
+
+/// Number of records of a stream which were fitted and written, and the numbers of the records
+/// which were skipped since they could not be fitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamSummary {
+    pub written: usize,
+    pub skipped: Vec<usize>,
+}
+
+/// Fits the rawacf records of `input` and writes the fitacf records to `output` in the same
+/// order, holding at most `window` records in memory at once. The output is flushed after every
+/// window, so a `window` of 1 passes on each record as soon as it is fitted.
+///
+/// Records are fitted in parallel within each window, unless `options.parallel_ranges` is set,
+/// in which case they are fitted one at a time with their range gates in parallel. A record
+/// which cannot be fitted, e.g. since it is invalid or has no hdw entry, is skipped with a
+/// warning, unless `strict` is set, in which case it ends the stream with an error. A record
+/// which cannot be read always ends the stream, since the records after it cannot be found, but
+/// the records read before it are fitted and written first.
+pub fn fit_stream(
+    input: impl Read,
+    output: impl Write,
+    hdw_provider: &mut HdwProvider,
+    options: &FitOptions,
+    window: usize,
+    strict: bool,
+) -> Result<StreamSummary, BackscatterError> {
+    let mut reader = RecordReader::<RawacfRecord, _>::new(input);
+    let mut writer = RecordWriter::new(output);
+    let mut workspace = FitWorkspace::new();
+    let mut records: Vec<RawacfRecord> = Vec::with_capacity(window.max(1));
+    let mut count = 0;
+    let mut summary = StreamSummary::default();
+
+    let mut read_error = None;
+    while read_error.is_none() {
+        records.clear();
+        for rec in reader.by_ref().take(window.max(1)) {
+            match rec {
+                Ok(rec) => records.push(rec),
+                Err(e) => {
+                    read_error = Some(BackscatterError::new(&format!(
+                        "Unable to read record {}: {}",
+                        count + records.len(),
+                        e.details
+                    )));
+                    break;
+                }
+            }
+        }
+        if records.is_empty() {
+            break;
+        }
+
+        // Resolve the hdw entry valid for each record's station and time
+        let loaded: Vec<Result<(), BackscatterError>> = records
+            .iter()
+            .map(|rec| hdw_provider.load_station(rec.station_id).map(|_| ()))
+            .collect();
+        let hdws: Vec<Result<&HdwInfo, BackscatterError>> = zip(records.iter(), loaded)
+            .map(|(rec, loaded)| {
+                loaded.and_then(|_| hdw_provider.lookup(rec.station_id, record_datetime(rec)?))
+            })
+            .collect();
+
+        let fitted: Vec<_> = if options.parallel_ranges {
+            records
+                .iter()
+                .zip(hdws.iter())
+                .map(|(rec, hdw)| fit_record(rec, hdw, options, &mut workspace))
+                .collect()
+        } else {
+            records
+                .par_iter()
+                .zip(hdws.par_iter())
+                .map_init(FitWorkspace::new, |workspace, (rec, hdw)| {
+                    fit_record(rec, hdw, options, workspace)
+                })
+                .collect()
+        };
+
+        for (i, rec) in fitted.into_iter().enumerate() {
+            match rec {
+                Ok(rec) => {
+                    writer.write(&rec)?;
+                    summary.written += 1;
+                }
+                Err(e) if strict => Err(BackscatterError::new(&format!(
+                    "Unable to fit record {}: {}",
+                    count + i,
+                    e
+                )))?,
+                Err(e) => {
+                    warn!(
+                        record = count + i,
+                        "Skipping record which cannot be fitted: {}", e
+                    );
+                    summary.skipped.push(count + i);
+                }
+            }
+        }
+        // Pass the window on to readers downstream, e.g. through a pipe
+        writer.flush()?;
+        count += records.len();
+    }
+    match read_error {
+        Some(e) => Err(e),
+        None => Ok(summary),
+    }
+}
+
+/// Fits a record with the hdw entry resolved for it, if one was found
+fn fit_record(
+    record: &RawacfRecord,
+    hdw: &Result<&HdwInfo, BackscatterError>,
+    options: &FitOptions,
+    workspace: &mut FitWorkspace,
+) -> Result<FitacfRecord, String> {
+    match hdw {
+        Ok(hdw) => fit_rawacf_record_in(record, hdw, options, workspace).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
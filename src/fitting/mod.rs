@@ -1 +1,3 @@
+pub mod acf_data;
 pub mod fitacf3;
+pub mod validation;
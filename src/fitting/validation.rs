@@ -0,0 +1,312 @@
+use crate::fitting::acf_data::AcfData;
+use chrono::NaiveDate;
+use dmap::formats::RawacfRecord;
+use dmap::{DmapVec, InDmap};
+use std::error::Error;
+use std::fmt;
+
+/// A way in which a record is unfit for fitting, either because its arrays disagree with each
+/// other or with the counts describing them, or because a parameter is outside its physical
+/// range.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// A parameter is outside the range of values it can physically take
+    OutOfRange {
+        field: &'static str,
+        value: f64,
+        expected: &'static str,
+    },
+    /// An array does not have the length implied by the other fields of the record
+    LengthMismatch {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The stated dimensions of an array do not match the number of elements it holds
+    DimensionMismatch {
+        field: &'static str,
+        dimensions: Vec<i32>,
+        len: usize,
+    },
+    /// An entry of the range list is not a range of the record
+    RangeOutOfBounds {
+        index: usize,
+        range: i16,
+        num_ranges: i16,
+    },
+    /// A lag of the lag table is made of pulses which are not in the pulse table
+    LagNotInPulseTable { lag: usize, pulses: [i16; 2] },
+    /// A lag of the lag table has its second pulse before its first
+    NegativeLag { lag: usize, pulses: [i16; 2] },
+    /// A value of a data array is NaN or infinite. Only reported, as the fit rejects such lags
+    NonFinite { field: &'static str, index: usize },
+    /// The timestamp of the record is not a valid date and time
+    InvalidTime(String),
+}
+
+impl Error for ValidationError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::OutOfRange {
+                field,
+                value,
+                expected,
+            } => write!(f, "{} is {}, expected {}", field, value, expected),
+            ValidationError::LengthMismatch {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} has {} elements, expected {}",
+                field, actual, expected
+            ),
+            ValidationError::DimensionMismatch {
+                field,
+                dimensions,
+                len,
+            } => write!(
+                f,
+                "{} has dimensions {:?} but {} elements",
+                field, dimensions, len
+            ),
+            ValidationError::RangeOutOfBounds {
+                index,
+                range,
+                num_ranges,
+            } => write!(
+                f,
+                "range_list[{}] is {}, outside the {} ranges of the record",
+                index, range, num_ranges
+            ),
+            ValidationError::LagNotInPulseTable { lag, pulses } => write!(
+                f,
+                "Lag {} is made of pulses {:?}, which are not in the pulse table",
+                lag, pulses
+            ),
+            ValidationError::NegativeLag { lag, pulses } => write!(
+                f,
+                "Lag {} is made of pulses {:?}, with the second before the first",
+                lag, pulses
+            ),
+            ValidationError::NonFinite { field, index } => {
+                write!(f, "{}[{}] is not finite", field, index)
+            }
+            ValidationError::InvalidTime(time) => write!(f, "Invalid record time {}", time),
+        }
+    }
+}
+
+/// Checks that a record can be fitted, returning the first problem found.
+pub fn validate(record: &impl AcfData) -> Result<(), ValidationError> {
+    match validation_errors(record).into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Checks that a rawacf record can be fitted, as `validate`, and additionally that its timestamp
+/// is valid and that each array matches its stated dimensions.
+pub fn validate_rawacf(record: &RawacfRecord) -> Result<(), ValidationError> {
+    match rawacf_validation_errors(record).into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Every problem which keeps a record from being fitted. Array contents are only checked once
+/// the counts describing the arrays are known to be consistent, so a record with inconsistent
+/// counts does not also report every element.
+pub fn validation_errors(record: &impl AcfData) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut at_least = |field: &'static str, value: f64, min: f64, expected: &'static str| {
+        if value < min {
+            errors.push(ValidationError::OutOfRange {
+                field,
+                value,
+                expected,
+            });
+        }
+    };
+    at_least(
+        "num_averages",
+        record.num_averages() as f64,
+        0.0,
+        "at least 0",
+    );
+    at_least("num_pulses", record.num_pulses() as f64, 0.0, "at least 0");
+    at_least("num_lags", record.num_lags() as f64, 0.0, "at least 0");
+    at_least("num_ranges", record.num_ranges() as f64, 0.0, "at least 0");
+    at_least(
+        "lag_to_first_range",
+        record.lag_to_first_range() as f64,
+        0.0,
+        "at least 0",
+    );
+    at_least(
+        "sample_separation",
+        record.sample_separation() as f64,
+        1.0,
+        "positive",
+    );
+    at_least(
+        "tx_pulse_length",
+        record.tx_pulse_length() as f64,
+        1.0,
+        "positive",
+    );
+    at_least(
+        "multi_pulse_increment",
+        record.multi_pulse_increment() as f64,
+        1.0,
+        "positive",
+    );
+    at_least("tx_freq", record.tx_freq() as f64, 1.0, "positive");
+    at_least("beam_num", record.beam_num() as f64, 0.0, "at least 0");
+    if !record.search_noise().is_finite() {
+        errors.push(ValidationError::OutOfRange {
+            field: "search_noise",
+            value: record.search_noise() as f64,
+            expected: "finite",
+        });
+    }
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    let num_pulses = record.num_pulses() as usize;
+    let num_lags = record.num_lags() as usize;
+    let num_ranges = record.num_ranges() as usize;
+    let num_fitted = record.range_list().len();
+    let mut check_len = |field: &'static str, expected: usize, actual: usize| {
+        if actual != expected {
+            errors.push(ValidationError::LengthMismatch {
+                field,
+                expected,
+                actual,
+            });
+        }
+    };
+    check_len("pulse_table", num_pulses, record.pulse_table().len());
+    check_len("lag_zero_power", num_ranges, record.lag_zero_power().len());
+    check_len("acfs", num_fitted * num_lags * 2, record.acfs().len());
+    if let Some(xcfs) = record.xcfs() {
+        check_len("xcfs", num_fitted * num_lags * 2, xcfs.len());
+    }
+    // Lag tables may hold rows past num_lags, e.g. an alternate lag zero
+    if record.lag_table().len() < 2 * num_lags {
+        errors.push(ValidationError::LengthMismatch {
+            field: "lag_table",
+            expected: 2 * num_lags,
+            actual: record.lag_table().len(),
+        });
+    }
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    if let Some(&pulse) = record.pulse_table().iter().find(|&&p| p < 0) {
+        errors.push(ValidationError::OutOfRange {
+            field: "pulse_table",
+            value: pulse as f64,
+            expected: "at least 0",
+        });
+    }
+    for (index, &range) in record.range_list().iter().enumerate() {
+        if range < 0 || range >= record.num_ranges() {
+            errors.push(ValidationError::RangeOutOfBounds {
+                index,
+                range,
+                num_ranges: record.num_ranges(),
+            });
+        }
+    }
+    for (lag, pulses) in record
+        .lag_table()
+        .chunks_exact(2)
+        .take(num_lags)
+        .enumerate()
+    {
+        let pulses = [pulses[0], pulses[1]];
+        if !pulses.iter().all(|p| record.pulse_table().contains(p)) {
+            errors.push(ValidationError::LagNotInPulseTable { lag, pulses });
+        } else if pulses[1] < pulses[0] {
+            errors.push(ValidationError::NegativeLag { lag, pulses });
+        }
+    }
+    errors
+}
+
+/// The first NaN or infinite value of each data array. These do not keep a record from being
+/// fitted, since the lags holding them are rejected during fitting, so are only reported.
+pub fn non_finite_errors(record: &impl AcfData) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut check_finite = |field: &'static str, values: &[f32]| {
+        if let Some(index) = values.iter().position(|x| !x.is_finite()) {
+            errors.push(ValidationError::NonFinite { field, index });
+        }
+    };
+    check_finite("lag_zero_power", record.lag_zero_power());
+    check_finite("acfs", record.acfs());
+    if let Some(xcfs) = record.xcfs() {
+        check_finite("xcfs", xcfs);
+    }
+    errors
+}
+
+/// Every problem which keeps a rawacf record from being fitted, as `validation_errors`, along
+/// with problems in its timestamp and in the dimensions of its arrays. Non-finite samples do not
+/// keep a record from being fitted, and are reported by `non_finite_errors` instead.
+pub fn rawacf_validation_errors(record: &RawacfRecord) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let time = NaiveDate::from_ymd_opt(record.year as i32, record.month as u32, record.day as u32)
+        .and_then(|d| {
+            d.and_hms_opt(
+                record.hour as u32,
+                record.minute as u32,
+                record.second as u32,
+            )
+        });
+    if time.is_none() || !(0..1_000_000).contains(&record.microsecond) {
+        errors.push(ValidationError::InvalidTime(format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+            record.year,
+            record.month,
+            record.day,
+            record.hour,
+            record.minute,
+            record.second,
+            record.microsecond
+        )));
+    }
+    check_dimensions("pulse_table", &record.pulse_table, &mut errors);
+    check_dimensions("lag_table", &record.lag_table, &mut errors);
+    check_dimensions("lag_zero_power", &record.lag_zero_power, &mut errors);
+    check_dimensions("range_list", &record.range_list, &mut errors);
+    check_dimensions("acfs", &record.acfs, &mut errors);
+    if let Some(xcfs) = &record.xcfs {
+        check_dimensions("xcfs", xcfs, &mut errors);
+    }
+    errors.extend(validation_errors(record));
+    errors
+}
+
+/// Checks that the dimensions of an array account for exactly the elements it holds
+fn check_dimensions<T: InDmap>(
+    field: &'static str,
+    array: &DmapVec<T>,
+    errors: &mut Vec<ValidationError>,
+) {
+    let len = array.dimensions.iter().try_fold(1_usize, |acc, &d| {
+        usize::try_from(d).ok().and_then(|d| acc.checked_mul(d))
+    });
+    if len != Some(array.data.len()) {
+        errors.push(ValidationError::DimensionMismatch {
+            field,
+            dimensions: array.dimensions.clone(),
+            len: array.data.len(),
+        });
+    }
+}
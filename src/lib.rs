@@ -1,3 +1,7 @@
+#[cfg(feature = "capi")]
+pub mod capi;
 pub mod error;
 pub mod fitting;
+#[cfg(feature = "python")]
+pub mod python;
 pub mod utils;
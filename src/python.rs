@@ -0,0 +1,377 @@
+use crate::error::BackscatterError;
+use crate::fitting::fitacf3::fitacf_v3::{
+    fit_rawacf_record_with_options, FitOptions, Fitacf3Error,
+};
+use crate::utils::compression::{self, Compression};
+use crate::utils::dmap_stream::RecordWriter;
+use crate::utils::hdw::HdwInfo;
+use chrono::{NaiveDate, NaiveDateTime};
+use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
+use dmap::{DmapVec, InDmap};
+use numpy::{Element, PyArray1, PyArray3, PyArrayMethods};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+impl From<Fitacf3Error> for PyErr {
+    fn from(err: Fitacf3Error) -> PyErr {
+        PyValueError::new_err(err.to_string())
+    }
+}
+
+impl From<BackscatterError> for PyErr {
+    fn from(err: BackscatterError) -> PyErr {
+        PyValueError::new_err(err.details)
+    }
+}
+
+fn to_array<'py, T: Element + InDmap + Copy>(
+    py: Python<'py>,
+    vals: &DmapVec<T>,
+) -> Bound<'py, PyArray1<T>> {
+    PyArray1::from_slice(py, &vals.data)
+}
+
+fn to_optional_array<'py, T: Element + InDmap + Copy>(
+    py: Python<'py>,
+    vals: &Option<DmapVec<T>>,
+) -> Option<Bound<'py, PyArray1<T>>> {
+    vals.as_ref().map(|v| to_array(py, v))
+}
+
+/// Timestamp of a record, at the start of the integration period
+fn record_datetime(
+    year: i16,
+    month: i16,
+    day: i16,
+    hour: i16,
+    minute: i16,
+    second: i16,
+    microsecond: i32,
+) -> PyResult<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+        .and_then(|d| {
+            d.and_hms_micro_opt(
+                hour as u32,
+                minute as u32,
+                second as u32,
+                microsecond as u32,
+            )
+        })
+        .ok_or_else(|| PyValueError::new_err("Unable to interpret record timestamp"))
+}
+
+/// A record read from a rawacf file
+#[pyclass(name = "RawacfRecord", module = "backscatter_rs", frozen)]
+pub struct PyRawacfRecord(pub RawacfRecord);
+
+#[pymethods]
+impl PyRawacfRecord {
+    #[getter]
+    fn station_id(&self) -> i16 {
+        self.0.station_id
+    }
+    #[getter]
+    fn beam_num(&self) -> i16 {
+        self.0.beam_num
+    }
+    #[getter]
+    fn channel(&self) -> i16 {
+        self.0.channel
+    }
+    #[getter]
+    fn tx_freq(&self) -> i16 {
+        self.0.tx_freq
+    }
+    #[getter]
+    fn num_averages(&self) -> i16 {
+        self.0.num_averages
+    }
+    #[getter]
+    fn num_lags(&self) -> i16 {
+        self.0.num_lags
+    }
+    #[getter]
+    fn datetime(&self) -> PyResult<NaiveDateTime> {
+        let r = &self.0;
+        record_datetime(
+            r.year,
+            r.month,
+            r.day,
+            r.hour,
+            r.minute,
+            r.second,
+            r.microsecond,
+        )
+    }
+    #[getter]
+    fn lag_zero_power<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lag_zero_power)
+    }
+    #[getter]
+    fn range_list<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<i16>> {
+        to_array(py, &self.0.range_list)
+    }
+    /// ACFs with shape `[range, lag, (real, imag)]`. Files list the dimensions in the reverse
+    /// order, so the shape comes from `range_list` and `num_lags` instead.
+    #[getter]
+    fn acfs<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray3<f32>>> {
+        let shape = [
+            self.0.range_list.data.len(),
+            self.0.num_lags.max(0) as usize,
+            2,
+        ];
+        if self.0.acfs.data.len() != shape.iter().product::<usize>() {
+            Err(PyValueError::new_err(format!(
+                "acfs has {} values, but range_list and num_lags give a shape of {:?}",
+                self.0.acfs.data.len(),
+                shape
+            )))?
+        }
+        to_array(py, &self.0.acfs).reshape(shape)
+    }
+    fn __repr__(&self) -> String {
+        format!(
+            "RawacfRecord(station_id={}, beam_num={}, time={:04}-{:02}-{:02}T{:02}:{:02}:{:02})",
+            self.0.station_id,
+            self.0.beam_num,
+            self.0.year,
+            self.0.month,
+            self.0.day,
+            self.0.hour,
+            self.0.minute,
+            self.0.second
+        )
+    }
+}
+
+/// A fitted record. The fitted parameters are returned as numpy arrays with one element per
+/// range in `range_list`.
+#[pyclass(name = "FitacfRecord", module = "backscatter_rs", frozen)]
+pub struct PyFitacfRecord(pub FitacfRecord);
+
+#[pymethods]
+impl PyFitacfRecord {
+    #[getter]
+    fn station_id(&self) -> i16 {
+        self.0.station_id
+    }
+    #[getter]
+    fn beam_num(&self) -> i16 {
+        self.0.beam_num
+    }
+    #[getter]
+    fn channel(&self) -> i16 {
+        self.0.channel
+    }
+    #[getter]
+    fn sky_noise(&self) -> f32 {
+        self.0.sky_noise
+    }
+    #[getter]
+    fn datetime(&self) -> PyResult<NaiveDateTime> {
+        let r = &self.0;
+        record_datetime(
+            r.year,
+            r.month,
+            r.day,
+            r.hour,
+            r.minute,
+            r.second,
+            r.microsecond,
+        )
+    }
+    #[getter]
+    fn lag_zero_power<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lag_zero_power)
+    }
+    #[getter]
+    fn range_list<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<i16>> {
+        to_array(py, &self.0.range_list)
+    }
+    #[getter]
+    fn fitted_points<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<i16>> {
+        to_array(py, &self.0.fitted_points)
+    }
+    #[getter]
+    fn quality_flag<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<i8>> {
+        to_array(py, &self.0.quality_flag)
+    }
+    #[getter]
+    fn ground_flag<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<i8>> {
+        to_array(py, &self.0.ground_flag)
+    }
+    #[getter]
+    fn lambda_power<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lambda_power)
+    }
+    #[getter]
+    fn lambda_power_error<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lambda_power_error)
+    }
+    #[getter]
+    fn sigma_power<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.sigma_power)
+    }
+    #[getter]
+    fn sigma_power_error<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.sigma_power_error)
+    }
+    #[getter]
+    fn velocity<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.velocity)
+    }
+    #[getter]
+    fn velocity_error<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.velocity_error)
+    }
+    #[getter]
+    fn lambda_spectral_width<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lambda_spectral_width)
+    }
+    #[getter]
+    fn lambda_spectral_width_error<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lambda_spectral_width_error)
+    }
+    #[getter]
+    fn sigma_spectral_width<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.sigma_spectral_width)
+    }
+    #[getter]
+    fn sigma_spectral_width_error<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.sigma_spectral_width_error)
+    }
+    #[getter]
+    fn lambda_std_dev<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.lambda_std_dev)
+    }
+    #[getter]
+    fn sigma_std_dev<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.sigma_std_dev)
+    }
+    #[getter]
+    fn phi_std_dev<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f32>> {
+        to_array(py, &self.0.phi_std_dev)
+    }
+    #[getter]
+    fn lag_zero_phi<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyArray1<f32>>> {
+        to_optional_array(py, &self.0.lag_zero_phi)
+    }
+    #[getter]
+    fn lag_zero_phi_error<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyArray1<f32>>> {
+        to_optional_array(py, &self.0.lag_zero_phi_error)
+    }
+    #[getter]
+    fn elevation<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyArray1<f32>>> {
+        to_optional_array(py, &self.0.elevation)
+    }
+    #[getter]
+    fn elevation_low<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyArray1<f32>>> {
+        to_optional_array(py, &self.0.elevation_low)
+    }
+    #[getter]
+    fn elevation_high<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyArray1<f32>>> {
+        to_optional_array(py, &self.0.elevation_high)
+    }
+    fn __eq__(&self, other: &PyFitacfRecord) -> bool {
+        self.0 == other.0
+    }
+    fn __repr__(&self) -> String {
+        format!(
+            "FitacfRecord(station_id={}, beam_num={}, time={:04}-{:02}-{:02}T{:02}:{:02}:{:02}, \
+             num_fitted={})",
+            self.0.station_id,
+            self.0.beam_num,
+            self.0.year,
+            self.0.month,
+            self.0.day,
+            self.0.hour,
+            self.0.minute,
+            self.0.second,
+            self.0.range_list.data.len()
+        )
+    }
+}
+
+#[pymethods]
+impl HdwInfo {
+    /// Looks up the hardware parameters of a radar at the given time, preferring the hdw file
+    /// in `hdw_dir` if given.
+    #[new]
+    #[pyo3(signature = (station_id, datetime, hdw_dir=None))]
+    fn py_new(
+        station_id: i16,
+        datetime: NaiveDateTime,
+        hdw_dir: Option<PathBuf>,
+    ) -> PyResult<HdwInfo> {
+        Ok(match hdw_dir {
+            Some(dir) => HdwInfo::with_override_dir(&dir, station_id, datetime)?,
+            None => HdwInfo::new(station_id, datetime)?,
+        })
+    }
+}
+
+/// Fits a rawacf record with the FITACF 3.0 algorithm.
+#[pyfunction]
+#[pyo3(signature = (record, hdw, clip_sigma=None))]
+fn fit_rawacf_record(
+    py: Python<'_>,
+    record: PyRef<'_, PyRawacfRecord>,
+    hdw: PyRef<'_, HdwInfo>,
+    clip_sigma: Option<f64>,
+) -> PyResult<PyFitacfRecord> {
+    let options = FitOptions {
+        clip_sigma,
+        ..Default::default()
+    };
+    let (record, hdw) = (&record.0, &*hdw);
+    let fitacf = py.detach(|| fit_rawacf_record_with_options(record, hdw, &options))?;
+    Ok(PyFitacfRecord(fitacf))
+}
+
+/// Reads all records of a rawacf file, which may be compressed.
+#[pyfunction]
+fn read_rawacf(path: PathBuf) -> PyResult<Vec<PyRawacfRecord>> {
+    let file = compression::open(&path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let records =
+        RawacfRecord::read_records(file).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    Ok(records.into_iter().map(PyRawacfRecord).collect())
+}
+
+/// Reads all records of a fitacf file, which may be compressed.
+#[pyfunction]
+fn read_fitacf(path: PathBuf) -> PyResult<Vec<PyFitacfRecord>> {
+    let file = compression::open(&path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    let records =
+        FitacfRecord::read_records(file).map_err(|e| PyIOError::new_err(e.to_string()))?;
+    Ok(records.into_iter().map(PyFitacfRecord).collect())
+}
+
+/// Writes records to a fitacf file, overwriting any existing file. Files ending in .bz2, .gz or
+/// .zst are compressed.
+#[pyfunction]
+fn write_fitacf(path: PathBuf, records: Vec<PyRef<'_, PyFitacfRecord>>) -> PyResult<()> {
+    let io_err = |e: BackscatterError| PyIOError::new_err(e.to_string());
+    let file = compression::create(&path, Compression::from_extension(&path)).map_err(io_err)?;
+    let mut writer = RecordWriter::new(file);
+    for rec in records.iter() {
+        writer.write(&rec.0).map_err(io_err)?;
+    }
+    writer
+        .into_inner()
+        .finish()
+        .map_err(|e| PyIOError::new_err(e.to_string()))?;
+    Ok(())
+}
+
+#[pymodule]
+fn backscatter_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyRawacfRecord>()?;
+    m.add_class::<PyFitacfRecord>()?;
+    m.add_class::<HdwInfo>()?;
+    m.add_function(wrap_pyfunction!(fit_rawacf_record, m)?)?;
+    m.add_function(wrap_pyfunction!(read_rawacf, m)?)?;
+    m.add_function(wrap_pyfunction!(read_fitacf, m)?)?;
+    m.add_function(wrap_pyfunction!(write_fitacf, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use crate::error::BackscatterError;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Compression of a data file. Archives typically store files compressed with bzip2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Bzip2,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Identifies the compression from the first bytes of a file.
+    pub fn from_magic(bytes: &[u8]) -> Compression {
+        match bytes {
+            [b'B', b'Z', b'h', ..] => Compression::Bzip2,
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// Identifies the compression from the extension of a file name, e.g. `.bz2`.
+    pub fn from_extension(path: &Path) -> Compression {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("bz2") => Compression::Bzip2,
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// File name extension of the compression, without the leading dot.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Bzip2 => Some("bz2"),
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Bzip2 => write!(f, "bz2"),
+            Compression::Gzip => write!(f, "gz"),
+            Compression::Zstd => write!(f, "zst"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BackscatterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "bz2" | "bzip2" => Ok(Compression::Bzip2),
+            "gz" | "gzip" => Ok(Compression::Gzip),
+            "zst" | "zstd" => Ok(Compression::Zstd),
+            _ => Err(BackscatterError::new(&format!(
+                "Unknown compression {}, expected one of none, bz2, gz or zst",
+                s
+            ))),
+        }
+    }
+}
+
+/// Wraps a reader in a decoder for its compression, detected from its first bytes. Readers of
+/// uncompressed data are returned as they are.
+pub fn decompress<'a>(mut reader: impl BufRead + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = Compression::from_magic(reader.fill_buf()?);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// Opens a file for reading, decompressing it if needed.
+pub fn open(path: &Path) -> Result<Box<dyn Read>, BackscatterError> {
+    let err =
+        |e: io::Error| BackscatterError::new(&format!("Unable to open {}: {}", path.display(), e));
+    let file = File::open(path).map_err(err)?;
+    decompress(BufReader::new(file)).map_err(err)
+}
+
+/// Creates a file for writing, compressed as given.
+pub fn create(
+    path: &Path,
+    compression: Compression,
+) -> Result<CompressedWriter<BufWriter<File>>, BackscatterError> {
+    let err = |e: io::Error| {
+        BackscatterError::new(&format!("Unable to create {}: {}", path.display(), e))
+    };
+    let file = File::create(path).map_err(err)?;
+    CompressedWriter::new(BufWriter::new(file), compression).map_err(err)
+}
+
+/// A writer which compresses everything written to it. `finish` must be called once done, to
+/// write the end of the compressed stream.
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<CompressedWriter<W>> {
+        Ok(match compression {
+            Compression::None => CompressedWriter::Plain(writer),
+            Compression::Bzip2 => CompressedWriter::Bzip2(bzip2::write::BzEncoder::new(
+                writer,
+                bzip2::Compression::default(),
+            )),
+            Compression::Gzip => CompressedWriter::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Writes the end of the compressed stream and flushes the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = match self {
+            CompressedWriter::Plain(w) => w,
+            CompressedWriter::Bzip2(w) => w.finish()?,
+            CompressedWriter::Gzip(w) => w.finish()?,
+            CompressedWriter::Zstd(w) => w.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(w) => w.write(buf),
+            CompressedWriter::Bzip2(w) => w.write(buf),
+            CompressedWriter::Gzip(w) => w.write(buf),
+            CompressedWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(w) => w.flush(),
+            CompressedWriter::Bzip2(w) => w.flush(),
+            CompressedWriter::Gzip(w) => w.flush(),
+            CompressedWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::error::BackscatterError;
+use dmap::formats::DmapRecord;
+use std::io::{ErrorKind, Read, Write};
+use std::marker::PhantomData;
+
+/// Length of the block header starting every DMAP record: the encoding code and the size of
+/// the record in bytes, including the header, as little-endian i32.
+const HEADER_LEN: usize = 8;
+
+/// Largest record accepted, to fail fast on corrupt sizes rather than allocating for them.
+pub const MAX_RECORD_SIZE: usize = 1 << 30;
+
+/// Reads the records of a DMAP stream one at a time, so only a single record is held in memory.
+pub struct RecordReader<T, R> {
+    reader: R,
+    /// Number of records read so far
+    count: usize,
+    done: bool,
+    _record: PhantomData<T>,
+}
+
+impl<T: DmapRecord, R: Read> RecordReader<T, R> {
+    pub fn new(reader: R) -> RecordReader<T, R> {
+        RecordReader {
+            reader,
+            count: 0,
+            done: false,
+            _record: PhantomData,
+        }
+    }
+
+    /// Reads the bytes of the next record, or returns `None` at the end of the stream.
+    fn next_block(&mut self) -> Result<Option<Vec<u8>>, BackscatterError> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => Err(self.error("Stream ends within a record header"))?,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => Err(self.error(&e.to_string()))?,
+            }
+        }
+        let size = i32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let size = usize::try_from(size)
+            .ok()
+            .filter(|&s| (HEADER_LEN..=MAX_RECORD_SIZE).contains(&s))
+            .ok_or_else(|| self.error(&format!("Invalid record size {}", size)))?;
+
+        let mut block = vec![0u8; size];
+        block[..HEADER_LEN].copy_from_slice(&header);
+        self.reader
+            .read_exact(&mut block[HEADER_LEN..])
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => self.error("Stream ends within a record"),
+                _ => self.error(&e.to_string()),
+            })?;
+        Ok(Some(block))
+    }
+
+    fn error(&self, msg: &str) -> BackscatterError {
+        BackscatterError::new(&format!("Record {}: {}", self.count, msg))
+    }
+}
+
+impl<T: DmapRecord, R: Read> Iterator for RecordReader<T, R> {
+    type Item = Result<T, BackscatterError>;
+
+    /// Reads the next record. Iteration stops after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let block = match self.next_block() {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let record = T::read_records(&block[..])
+            .map_err(|e| self.error(&e.to_string()))
+            .and_then(|mut records| match records.len() {
+                1 => Ok(records.remove(0)),
+                n => Err(self.error(&format!("Expected one record in block, found {}", n))),
+            });
+        self.done = record.is_err();
+        self.count += 1;
+        Some(record)
+    }
+}
+
+/// Writes DMAP records to a stream as they are produced.
+pub struct RecordWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W) -> RecordWriter<W> {
+        RecordWriter { writer }
+    }
+
+    pub fn write(&mut self, record: &impl DmapRecord) -> Result<(), BackscatterError> {
+        self.writer
+            .write_all(&record.to_bytes())
+            .map_err(|e| BackscatterError::new(&format!("Unable to write record: {}", e)))
+    }
+
+    pub fn flush(&mut self) -> Result<(), BackscatterError> {
+        self.writer
+            .flush()
+            .map_err(|e| BackscatterError::new(&format!("Unable to write records: {}", e)))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
@@ -1,13 +1,30 @@
 use crate::error::BackscatterError;
-use chrono::NaiveDateTime;
+use crate::utils::hdw_format::parse_lines;
+use crate::utils::stations::StationRegistry;
+use chrono::{NaiveDate, NaiveDateTime};
+use dmap::formats::RawacfRecord;
 use rust_embed::RustEmbed;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Version of the embedded hdw files, from the VERSION file of the snapshot.
+pub const HDW_VERSION: &str = env!("HDW_VERSION");
+
+/// Environment variable naming a directory of hdw files which take precedence over the
+/// embedded copies.
+pub const HDW_DIR_ENV: &str = "BACKSCATTER_HDW_DIR";
 
 #[derive(RustEmbed)]
-#[folder = "target/hdw/"]
+#[folder = "$HDW_EMBED_DIR"]
 struct Hdw;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "python", pyo3::pyclass(get_all, module = "backscatter_rs"))]
 pub struct HdwInfo {
     pub station_id: i16,
     pub valid_from: NaiveDateTime,
@@ -32,136 +49,230 @@ pub struct HdwInfo {
 }
 
 impl HdwInfo {
+    /// Looks up the hardware parameters of a station at a time. The hdw file is taken from the
+    /// directory named by `BACKSCATTER_HDW_DIR` if it is set and holds a file for the station,
+    /// and from the copy embedded at compile time otherwise.
     pub fn new(station_id: i16, datetime: NaiveDateTime) -> Result<HdwInfo, BackscatterError> {
-        let site_name = match station_id {
-            209 => "ade",
-            208 => "adw",
-            33 => "bks",
-            24 => "bpk",
-            66 => "cly",
-            207 => "cve",
-            206 => "cvw",
-            96 => "dce",
-            97 => "dcn",
-            512 => "ekb",
-            205 => "fhe",
-            204 => "fhw",
-            21 => "fir",
-            1 => "gbr",
-            4 => "hal",
-            10 => "han",
-            41 => "hkw",
-            40 => "hok",
-            211 => "ice",
-            210 => "icw",
-            64 => "inv",
-            50 => "jme",
-            3 => "kap",
-            15 => "ker",
-            7 => "kod",
-            16 => "ksr",
-            90 => "lyr",
-            20 => "mcm",
-            6 => "pgr",
-            9 => "pyk",
-            65 => "rkn",
-            11 => "san",
-            5 => "sas",
-            2 => "sch",
-            22 => "sps",
-            8 => "sto",
-            13 => "sye",
-            12 => "sys",
-            14 => "tig",
-            0 => "tst",
-            18 => "unw",
-            32 => "wal",
-            19 => "zho",
-            _ => Err(BackscatterError::new("Invalid station id"))?,
-        };
-        let hdw_file = Hdw::get(format!("hdw.dat.{}", site_name).as_str()).unwrap();
-        let mut hdw_params: Vec<HdwInfo> = vec![];
-        let reader = BufReader::new(hdw_file.data.as_ref()).lines();
-        for line in reader {
-            let line =
-                line.map_err(|_| BackscatterError::new("Unable to read line from hdw file"))?;
-            if !line.starts_with('#') {
-                let elements: Vec<&str> = line.split_whitespace().collect();
-                let date = elements[2];
-                let time = elements[3];
-                let validity_date = NaiveDateTime::parse_from_str(
-                    format!("{} {}", date, time).as_str(),
-                    "%Y%m%d %H:%M:%S",
-                )
-                .map_err(|_| BackscatterError::new("Unable to read station id from hdw file"))?;
-
-                if datetime < validity_date {
-                    break;
-                } //
-                hdw_params.push(HdwInfo {
-                    station_id: elements[0].parse::<i16>().map_err(|_| {
-                        BackscatterError::new("Unable to read station id from hdw file")
-                    })?,
-                    valid_from: validity_date,
-                    latitude: elements[4].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read latitude from hdw file")
-                    })?,
-                    longitude: elements[5].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read longitude from hdw file")
-                    })?,
-                    altitude: elements[6].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read altitude from hdw file")
-                    })?,
-                    boresight: elements[7].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read boresight from hdw file")
-                    })?,
-                    boresight_shift: elements[8].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read boresightshift from hdw file")
-                    })?,
-                    beam_separation: elements[9].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read beam separation from hdw file")
-                    })?,
-                    velocity_sign: elements[10].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read velocity sign from hdw file")
-                    })?,
-                    phase_sign: elements[11].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read phase sign from hdw file")
-                    })?,
-                    tdiff_a: elements[12].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read tdiff A from hdw file")
-                    })?,
-                    tdiff_b: elements[13].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read tdiff B from hdw file")
-                    })?,
-                    intf_offset_x: elements[14].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read intf offset X from hdw file")
-                    })?,
-                    intf_offset_y: elements[15].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read intf offset Y from hdw file")
-                    })?,
-                    intf_offset_z: elements[16].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read intf offset Z from hdw file")
-                    })?,
-                    rx_rise_time: elements[17].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read rx rise time from hdw file")
-                    })?,
-                    rx_atten_step: elements[18].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to read rx attenuation from hdw file")
-                    })?,
-                    attenuation_stages: elements[19].parse::<f32>().map_err(|_| {
-                        BackscatterError::new("Unable to attenuation stages from hdw file")
-                    })?,
-                    max_num_ranges: elements[20].parse::<i16>().map_err(|_| {
-                        BackscatterError::new("Unable to read max number of ranges from hdw file")
-                    })?,
-                    max_num_beams: elements[21].parse::<i16>().map_err(|_| {
-                        BackscatterError::new("Unable to read max number of beams from hdw file")
-                    })?,
-                })
+        HdwInfo::select(HdwSource::Default.entries(station_id)?, datetime)
+    }
+
+    /// Looks up the hardware parameters of a station from the embedded hdw files only.
+    pub fn embedded(station_id: i16, datetime: NaiveDateTime) -> Result<HdwInfo, BackscatterError> {
+        HdwInfo::select(embedded_entries(station_id)?, datetime)
+    }
+
+    /// Looks up the hardware parameters of a station from the hdw file in `dir`.
+    pub fn from_dir(
+        dir: &Path,
+        station_id: i16,
+        datetime: NaiveDateTime,
+    ) -> Result<HdwInfo, BackscatterError> {
+        HdwInfo::from_file(&dir.join(hdw_file_name(station_id)?), station_id, datetime)
+    }
+
+    /// Looks up the hardware parameters of a station from the hdw file in `dir`, falling back to
+    /// the embedded hdw file if `dir` has none for the station.
+    pub fn with_override_dir(
+        dir: &Path,
+        station_id: i16,
+        datetime: NaiveDateTime,
+    ) -> Result<HdwInfo, BackscatterError> {
+        HdwInfo::select(
+            HdwSource::Dir(dir.to_path_buf()).entries(station_id)?,
+            datetime,
+        )
+    }
+
+    /// Reads the hardware parameters of a station valid at a time from a single hdw file,
+    /// ignoring the entries of any other station, as `HdwSource::File` does.
+    pub fn from_file(
+        path: &Path,
+        station_id: i16,
+        datetime: NaiveDateTime,
+    ) -> Result<HdwInfo, BackscatterError> {
+        HdwInfo::select(
+            HdwSource::File(path.to_path_buf()).entries(station_id)?,
+            datetime,
+        )
+    }
+
+    /// Parses the contents of an hdw file, returning the last entry valid at `datetime`.
+    pub fn parse(
+        reader: impl BufRead,
+        datetime: NaiveDateTime,
+    ) -> Result<HdwInfo, BackscatterError> {
+        HdwInfo::select(HdwInfo::parse_all(reader)?, datetime)
+    }
+
+    /// The numeric parameters of the entry, by name, in the order of the hdw file columns.
+    pub fn parameters(&self) -> [(&'static str, f32); 19] {
+        [
+            ("station_id", self.station_id as f32),
+            ("latitude", self.latitude),
+            ("longitude", self.longitude),
+            ("altitude", self.altitude),
+            ("boresight", self.boresight),
+            ("boresight_shift", self.boresight_shift),
+            ("beam_separation", self.beam_separation),
+            ("velocity_sign", self.velocity_sign),
+            ("phase_sign", self.phase_sign),
+            ("tdiff_a", self.tdiff_a),
+            ("tdiff_b", self.tdiff_b),
+            ("intf_offset_x", self.intf_offset_x),
+            ("intf_offset_y", self.intf_offset_y),
+            ("intf_offset_z", self.intf_offset_z),
+            ("rx_rise_time", self.rx_rise_time),
+            ("rx_atten_step", self.rx_atten_step),
+            ("attenuation_stages", self.attenuation_stages),
+            ("max_num_ranges", self.max_num_ranges as f32),
+            ("max_num_beams", self.max_num_beams as f32),
+        ]
+    }
+
+    /// Takes the entry valid at `datetime` out of entries ordered by `valid_from`.
+    fn select(entries: Vec<HdwInfo>, datetime: NaiveDateTime) -> Result<HdwInfo, BackscatterError> {
+        entries
+            .into_iter()
+            .rev()
+            .find(|e| e.valid_from <= datetime)
+            .ok_or_else(|| BackscatterError::new("No valid lines found in hdw file"))
+    }
+
+    /// Parses every entry of an hdw file, ordered by the time they become valid.
+    pub fn parse_all(reader: impl BufRead) -> Result<Vec<HdwInfo>, BackscatterError> {
+        let mut hdw_params: Vec<HdwInfo> =
+            parse_lines(reader)?.into_iter().map(|l| l.hdw).collect();
+        hdw_params.sort_by_key(|h| h.valid_from);
+        Ok(hdw_params)
+    }
+}
+
+/// Where an `HdwProvider` reads hdw files from
+#[derive(Debug, Clone, Default)]
+pub enum HdwSource {
+    /// The directory named by `BACKSCATTER_HDW_DIR` if it is set, then the embedded files
+    #[default]
+    Default,
+    /// A directory whose files take precedence over the embedded ones
+    Dir(PathBuf),
+    /// A single hdw file, used for the station it describes
+    File(PathBuf),
+}
+
+impl HdwSource {
+    /// Reads every hdw entry of a station, ordered by the time they become valid.
+    pub fn entries(&self, station_id: i16) -> Result<Vec<HdwInfo>, BackscatterError> {
+        match self {
+            HdwSource::Default => match env::var_os(HDW_DIR_ENV) {
+                Some(dir) => HdwSource::Dir(PathBuf::from(dir)).entries(station_id),
+                None => embedded_entries(station_id),
+            },
+            HdwSource::Dir(dir) => {
+                let path = dir.join(hdw_file_name(station_id)?);
+                if path.is_file() {
+                    file_entries(&path)
+                } else {
+                    embedded_entries(station_id)
+                }
+            }
+            HdwSource::File(path) => {
+                let mut entries = file_entries(path)?;
+                entries.retain(|e| e.station_id == station_id);
+                if entries.is_empty() {
+                    Err(BackscatterError::new(&format!(
+                        "No entries for station {} in {}",
+                        station_id,
+                        path.display()
+                    )))?
+                }
+                Ok(entries)
             }
         }
-        hdw_params
-            .pop()
-            .ok_or_else(|| BackscatterError::new("No valid lines found in hdw file"))
     }
 }
+
+/// Caches the hdw entries of each station, so that every record can be fitted with the
+/// parameters valid for its own station and time.
+#[derive(Debug, Default)]
+pub struct HdwProvider {
+    source: HdwSource,
+    stations: HashMap<i16, Vec<HdwInfo>>,
+}
+
+impl HdwProvider {
+    pub fn new(source: HdwSource) -> HdwProvider {
+        HdwProvider {
+            source,
+            stations: HashMap::new(),
+        }
+    }
+
+    /// Reads the hdw entries of a station, if they are not already cached.
+    pub fn load_station(&mut self, station_id: i16) -> Result<&[HdwInfo], BackscatterError> {
+        let entries = match self.stations.entry(station_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(self.source.entries(station_id)?),
+        };
+        Ok(entries)
+    }
+
+    /// The hdw entry of a station valid at a time, reading the station's hdw file if needed.
+    pub fn get(
+        &mut self,
+        station_id: i16,
+        datetime: NaiveDateTime,
+    ) -> Result<&HdwInfo, BackscatterError> {
+        self.load_station(station_id)?;
+        self.lookup(station_id, datetime)
+    }
+
+    /// The hdw entry of a station valid at a time. The station must already have been loaded
+    /// with `load_station` or `get`.
+    pub fn lookup(
+        &self,
+        station_id: i16,
+        datetime: NaiveDateTime,
+    ) -> Result<&HdwInfo, BackscatterError> {
+        let entries = self.stations.get(&station_id).ok_or_else(|| {
+            BackscatterError::new(&format!("Hdw file of station {} not loaded", station_id))
+        })?;
+        entries
+            .iter()
+            .rev()
+            .find(|e| e.valid_from <= datetime)
+            .ok_or_else(|| {
+                BackscatterError::new(&format!(
+                    "No hdw entry for station {} valid at {}",
+                    station_id, datetime
+                ))
+            })
+    }
+}
+
+/// Timestamp of a rawacf record, to the second
+pub fn record_datetime(rec: &RawacfRecord) -> Result<NaiveDateTime, BackscatterError> {
+    NaiveDate::from_ymd_opt(rec.year as i32, rec.month as u32, rec.day as u32)
+        .and_then(|d| d.and_hms_opt(rec.hour as u32, rec.minute as u32, rec.second as u32))
+        .ok_or_else(|| BackscatterError::new("Unable to interpret record timestamp"))
+}
+
+fn embedded_entries(station_id: i16) -> Result<Vec<HdwInfo>, BackscatterError> {
+    let file_name = hdw_file_name(station_id)?;
+    let hdw_file = Hdw::get(&file_name)
+        .ok_or_else(|| BackscatterError::new(&format!("No embedded hdw file {}", file_name)))?;
+    HdwInfo::parse_all(BufReader::new(hdw_file.data.as_ref()))
+}
+
+fn file_entries(path: &Path) -> Result<Vec<HdwInfo>, BackscatterError> {
+    let file = File::open(path)
+        .map_err(|e| BackscatterError::new(&format!("Unable to open {}: {}", path.display(), e)))?;
+    HdwInfo::parse_all(BufReader::new(file))
+}
+
+/// Name of the hdw file of a station
+fn hdw_file_name(station_id: i16) -> Result<String, BackscatterError> {
+    StationRegistry::embedded()
+        .by_id(station_id)
+        .map(|station| station.hdw_file.clone())
+        .ok_or_else(|| BackscatterError::new(&format!("Invalid station id {}", station_id)))
+}
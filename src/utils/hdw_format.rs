@@ -0,0 +1,390 @@
+//! Parser for the SuperDARN hdw file format.
+//!
+//! Two layouts of hdw lines are in use:
+//!
+//! - `Current`, the layout of the hdw repository since 2021, with 22 columns: station id,
+//!   status, date (YYYYMMDD), time (HH:MM:SS), latitude, longitude, altitude, boresight,
+//!   boresight shift, beam separation, velocity sign, phase sign, tdiff A, tdiff B,
+//!   interferometer X, Y and Z offsets, receiver rise time, attenuator step, attenuation
+//!   stages, maximum number of ranges and maximum number of beams.
+//! - `Legacy`, the layout of RST 4 hdw.dat files, with 19 columns: station id, year, second of
+//!   the year, latitude, longitude, altitude, boresight, beam separation, velocity sign,
+//!   attenuator step, tdiff, phase sign, interferometer X, Y and Z offsets, receiver rise time,
+//!   attenuation stages, maximum number of ranges and maximum number of beams.
+//!
+//! Columns beyond those of the layout are ignored, so that files from newer versions of the
+//! format can still be read.
+use crate::error::BackscatterError;
+use crate::utils::hdw::HdwInfo;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use std::fmt;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdwFormat {
+    Legacy,
+    Current,
+}
+
+impl HdwFormat {
+    /// Number of columns in a line of this layout
+    pub fn num_columns(&self) -> usize {
+        match self {
+            HdwFormat::Legacy => 19,
+            HdwFormat::Current => 22,
+        }
+    }
+
+    /// Guesses the layout of a line from its fourth column, which is a time in the current
+    /// layout.
+    fn detect(columns: &[&str]) -> HdwFormat {
+        match columns.get(3) {
+            Some(time) if time.contains(':') => HdwFormat::Current,
+            _ => HdwFormat::Legacy,
+        }
+    }
+}
+
+/// An error in an hdw file, at a 1-based line and column
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdwParseError {
+    pub line: usize,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for HdwParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "line {}, column {}: {}", self.line, column, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl std::error::Error for HdwParseError {}
+
+impl From<HdwParseError> for BackscatterError {
+    fn from(err: HdwParseError) -> Self {
+        BackscatterError::new(&format!("Invalid hdw file at {}", err))
+    }
+}
+
+/// An entry of an hdw file with where it was read from
+#[derive(Debug, Clone)]
+pub struct HdwLine {
+    pub line: usize,
+    pub format: HdwFormat,
+    /// Number of columns beyond those of the layout
+    pub extra_columns: usize,
+    pub hdw: HdwInfo,
+}
+
+/// Splits a line into whitespace-separated columns, keeping their 1-based positions.
+struct Columns<'a> {
+    line: usize,
+    columns: Vec<&'a str>,
+}
+
+impl<'a> Columns<'a> {
+    fn get(&self, idx: usize, name: &str) -> Result<&'a str, HdwParseError> {
+        self.columns.get(idx).copied().ok_or_else(|| HdwParseError {
+            line: self.line,
+            column: Some(idx + 1),
+            message: format!("missing {}", name),
+        })
+    }
+
+    fn parse<T: std::str::FromStr>(&self, idx: usize, name: &str) -> Result<T, HdwParseError> {
+        let value = self.get(idx, name)?;
+        value.parse().map_err(|_| HdwParseError {
+            line: self.line,
+            column: Some(idx + 1),
+            message: format!("unable to read {} from '{}'", name, value),
+        })
+    }
+
+    fn error(&self, idx: usize, message: String) -> HdwParseError {
+        HdwParseError {
+            line: self.line,
+            column: Some(idx + 1),
+            message,
+        }
+    }
+}
+
+/// Parses one line of an hdw file. Returns `None` for comments and blank lines.
+pub fn parse_line(line_num: usize, line: &str) -> Result<Option<HdwLine>, HdwParseError> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let columns: Vec<&str> = line.split_whitespace().collect();
+    let format = HdwFormat::detect(&columns);
+    let c = Columns {
+        line: line_num,
+        columns,
+    };
+    let station_id = c.parse(0, "station id")?;
+    let hdw = match format {
+        HdwFormat::Current => {
+            let date = c.get(2, "date")?;
+            let time = c.get(3, "time")?;
+            let valid_from = NaiveDateTime::parse_from_str(
+                format!("{} {}", date, time).as_str(),
+                "%Y%m%d %H:%M:%S",
+            )
+            .map_err(|_| c.error(2, format!("unable to read date from '{} {}'", date, time)))?;
+            HdwInfo {
+                station_id,
+                valid_from,
+                latitude: c.parse(4, "latitude")?,
+                longitude: c.parse(5, "longitude")?,
+                altitude: c.parse(6, "altitude")?,
+                boresight: c.parse(7, "boresight")?,
+                boresight_shift: c.parse(8, "boresight shift")?,
+                beam_separation: c.parse(9, "beam separation")?,
+                velocity_sign: c.parse(10, "velocity sign")?,
+                phase_sign: c.parse(11, "phase sign")?,
+                tdiff_a: c.parse(12, "tdiff A")?,
+                tdiff_b: c.parse(13, "tdiff B")?,
+                intf_offset_x: c.parse(14, "interferometer offset X")?,
+                intf_offset_y: c.parse(15, "interferometer offset Y")?,
+                intf_offset_z: c.parse(16, "interferometer offset Z")?,
+                rx_rise_time: c.parse(17, "rx rise time")?,
+                rx_atten_step: c.parse(18, "rx attenuation step")?,
+                attenuation_stages: c.parse(19, "attenuation stages")?,
+                max_num_ranges: c.parse(20, "max number of ranges")?,
+                max_num_beams: c.parse(21, "max number of beams")?,
+            }
+        }
+        HdwFormat::Legacy => {
+            let year: i32 = c.parse(1, "year")?;
+            let year_second: i64 = c.parse(2, "second of year")?;
+            if !(0..=366 * 86400).contains(&year_second) {
+                Err(c.error(2, format!("second of year {} is out of range", year_second)))?
+            }
+            let valid_from = NaiveDate::from_ymd_opt(year, 1, 1)
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+                .and_then(|d| d.checked_add_signed(Duration::seconds(year_second)))
+                .ok_or_else(|| c.error(1, format!("invalid year {}", year)))?;
+            let tdiff = c.parse(10, "tdiff")?;
+            HdwInfo {
+                station_id,
+                valid_from,
+                latitude: c.parse(3, "latitude")?,
+                longitude: c.parse(4, "longitude")?,
+                altitude: c.parse(5, "altitude")?,
+                boresight: c.parse(6, "boresight")?,
+                boresight_shift: 0.0,
+                beam_separation: c.parse(7, "beam separation")?,
+                velocity_sign: c.parse(8, "velocity sign")?,
+                phase_sign: c.parse(11, "phase sign")?,
+                tdiff_a: tdiff,
+                tdiff_b: tdiff,
+                intf_offset_x: c.parse(12, "interferometer offset X")?,
+                intf_offset_y: c.parse(13, "interferometer offset Y")?,
+                intf_offset_z: c.parse(14, "interferometer offset Z")?,
+                rx_rise_time: c.parse(15, "rx rise time")?,
+                rx_atten_step: c.parse(9, "rx attenuation step")?,
+                attenuation_stages: c.parse(16, "attenuation stages")?,
+                max_num_ranges: c.parse(17, "max number of ranges")?,
+                max_num_beams: c.parse(18, "max number of beams")?,
+            }
+        }
+    };
+    Ok(Some(HdwLine {
+        line: line_num,
+        format,
+        extra_columns: c.columns.len().saturating_sub(format.num_columns()),
+        hdw,
+    }))
+}
+
+/// Parses every entry of an hdw file, in file order.
+pub fn parse_lines(reader: impl BufRead) -> Result<Vec<HdwLine>, HdwParseError> {
+    let mut lines = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| HdwParseError {
+            line: i + 1,
+            column: None,
+            message: format!("unable to read line: {}", e),
+        })?;
+        if let Some(hdw_line) = parse_line(i + 1, &line)? {
+            lines.push(hdw_line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Checks that the parameters of an entry are physically plausible, returning a description
+/// of each problem found.
+pub fn sanity_check(hdw: &HdwInfo) -> Vec<String> {
+    let mut problems = vec![];
+    if !(-90.0..=90.0).contains(&hdw.latitude) {
+        problems.push(format!("latitude {} is outside [-90, 90]", hdw.latitude));
+    }
+    if !(-180.0..=360.0).contains(&hdw.longitude) {
+        problems.push(format!(
+            "longitude {} is outside [-180, 360]",
+            hdw.longitude
+        ));
+    }
+    if !(-180.0..=360.0).contains(&hdw.boresight) {
+        problems.push(format!(
+            "boresight {} is outside [-180, 360]",
+            hdw.boresight
+        ));
+    }
+    if !(hdw.beam_separation > 0.0 && hdw.beam_separation <= 10.0) {
+        problems.push(format!(
+            "beam separation {} is outside (0, 10] degrees",
+            hdw.beam_separation
+        ));
+    }
+    if !(1..=64).contains(&hdw.max_num_beams) {
+        problems.push(format!(
+            "max number of beams {} is outside [1, 64]",
+            hdw.max_num_beams
+        ));
+    }
+    if hdw.max_num_ranges < 1 {
+        problems.push(format!(
+            "max number of ranges {} is not positive",
+            hdw.max_num_ranges
+        ));
+    }
+    for (name, sign) in [
+        ("velocity sign", hdw.velocity_sign),
+        ("phase sign", hdw.phase_sign),
+    ] {
+        if sign != 1.0 && sign != -1.0 {
+            problems.push(format!("{} {} is not 1 or -1", name, sign));
+        }
+    }
+    for (name, offset) in [
+        ("X", hdw.intf_offset_x),
+        ("Y", hdw.intf_offset_y),
+        ("Z", hdw.intf_offset_z),
+    ] {
+        if !offset.is_finite() || offset.abs() > 500.0 {
+            problems.push(format!(
+                "interferometer offset {} of {} m is beyond 500 m",
+                name, offset
+            ));
+        }
+    }
+    problems
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found by `lint`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintIssue {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(
+                f,
+                "{}:{}: {}: {}",
+                self.line, column, self.severity, self.message
+            ),
+            None => write!(f, "{}: {}: {}", self.line, self.severity, self.message),
+        }
+    }
+}
+
+/// Checks an hdw file for lines which cannot be parsed, implausible parameters, entries out of
+/// chronological order and, if `station_id` is given, entries of another station.
+pub fn lint(reader: impl BufRead, station_id: Option<i16>) -> Vec<LintIssue> {
+    let mut issues = vec![];
+    let mut previous: Option<NaiveDateTime> = None;
+    let mut format: Option<HdwFormat> = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line_num = i + 1;
+        let issue = |severity, column, message| LintIssue {
+            severity,
+            line: line_num,
+            column,
+            message,
+        };
+        let hdw_line = match line {
+            Ok(line) => match parse_line(line_num, &line) {
+                Ok(Some(hdw_line)) => hdw_line,
+                Ok(None) => continue,
+                Err(e) => {
+                    issues.push(issue(Severity::Error, e.column, e.message));
+                    continue;
+                }
+            },
+            Err(e) => {
+                issues.push(issue(
+                    Severity::Error,
+                    None,
+                    format!("unable to read line: {}", e),
+                ));
+                break;
+            }
+        };
+        let hdw = &hdw_line.hdw;
+        if hdw_line.extra_columns > 0 {
+            issues.push(issue(
+                Severity::Warning,
+                Some(hdw_line.format.num_columns() + 1),
+                format!("{} unrecognized trailing columns", hdw_line.extra_columns),
+            ));
+        }
+        if format.is_some_and(|f| f != hdw_line.format) {
+            issues.push(issue(
+                Severity::Warning,
+                None,
+                "line layout differs from earlier lines".to_string(),
+            ));
+        }
+        format = Some(hdw_line.format);
+        if let Some(id) = station_id {
+            if hdw.station_id != id {
+                issues.push(issue(
+                    Severity::Error,
+                    Some(1),
+                    format!("station id {} does not match {}", hdw.station_id, id),
+                ));
+            }
+        }
+        if previous.is_some_and(|p| hdw.valid_from <= p) {
+            issues.push(issue(
+                Severity::Error,
+                None,
+                format!(
+                    "entry valid from {} is not after the previous entry",
+                    hdw.valid_from
+                ),
+            ));
+        }
+        previous = Some(hdw.valid_from);
+        for problem in sanity_check(hdw) {
+            issues.push(issue(Severity::Warning, None, problem));
+        }
+    }
+    issues
+}
@@ -0,0 +1,107 @@
+use crate::error::BackscatterError;
+use crate::utils::hdw::{HdwInfo, HdwSource};
+use chrono::NaiveDateTime;
+use std::iter::zip;
+
+/// An hdw entry together with the period it applies to
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HdwPeriod {
+    pub start: NaiveDateTime,
+    /// Start of the next entry, or `None` if the entry is still valid
+    pub end: Option<NaiveDateTime>,
+    pub hdw: HdwInfo,
+}
+
+impl HdwPeriod {
+    pub fn contains(&self, datetime: NaiveDateTime) -> bool {
+        match self.end {
+            Some(end) => self.start <= datetime && datetime < end,
+            None => self.start <= datetime,
+        }
+    }
+}
+
+/// A parameter which changed value at the start of an hdw entry
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HdwChange {
+    pub time: NaiveDateTime,
+    pub parameter: String,
+    pub old: f32,
+    pub new: f32,
+}
+
+/// Every hdw entry of a station, as consecutive validity periods.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HdwHistory {
+    pub station_id: i16,
+    periods: Vec<HdwPeriod>,
+}
+
+impl HdwHistory {
+    /// Reads the history of a station from an hdw source.
+    pub fn load(source: &HdwSource, station_id: i16) -> Result<HdwHistory, BackscatterError> {
+        Ok(HdwHistory::from_entries(
+            station_id,
+            source.entries(station_id)?,
+        ))
+    }
+
+    /// Builds the history from entries ordered by `valid_from`, as returned by
+    /// `HdwInfo::parse_all`. Each period ends where the next one starts.
+    pub fn from_entries(station_id: i16, entries: Vec<HdwInfo>) -> HdwHistory {
+        let ends: Vec<Option<NaiveDateTime>> = entries
+            .iter()
+            .skip(1)
+            .map(|e| Some(e.valid_from))
+            .chain([None])
+            .collect();
+        let periods = zip(entries, ends)
+            .map(|(hdw, end)| HdwPeriod {
+                start: hdw.valid_from,
+                end,
+                hdw,
+            })
+            .collect();
+        HdwHistory {
+            station_id,
+            periods,
+        }
+    }
+
+    pub fn periods(&self) -> &[HdwPeriod] {
+        &self.periods
+    }
+
+    /// The period containing `datetime`, if any.
+    pub fn at(&self, datetime: NaiveDateTime) -> Option<&HdwPeriod> {
+        self.periods.iter().find(|p| p.contains(datetime))
+    }
+
+    /// Every parameter change which took effect after `from` and up to and including `to`, in
+    /// order of time.
+    pub fn changes_between(&self, from: NaiveDateTime, to: NaiveDateTime) -> Vec<HdwChange> {
+        let mut changes = vec![];
+        for pair in self.periods.windows(2) {
+            let (old, new) = (&pair[0], &pair[1]);
+            if new.start <= from || new.start > to {
+                continue;
+            }
+            for ((parameter, old_value), (_, new_value)) in
+                zip(old.hdw.parameters(), new.hdw.parameters())
+            {
+                if old_value != new_value {
+                    changes.push(HdwChange {
+                        time: new.start,
+                        parameter: parameter.to_string(),
+                        old: old_value,
+                        new: new_value,
+                    });
+                }
+            }
+        }
+        changes
+    }
+}
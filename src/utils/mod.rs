@@ -1 +1,8 @@
+pub mod compression;
+pub mod dmap_stream;
 pub mod hdw;
+pub mod hdw_format;
+pub mod hdw_history;
+#[cfg(feature = "serde")]
+pub mod non_finite;
+pub mod stations;
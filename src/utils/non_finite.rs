@@ -0,0 +1,85 @@
+//! Serde helpers for floats which may be NaN or infinite. JSON has no numbers for these, so
+//! `serde_json` would write them as `null` and then refuse to read them back. Non-finite values
+//! are written as the strings `"NaN"`, `"inf"` and `"-inf"` instead, and either numbers or those
+//! strings are accepted when reading.
+//!
+//! Use with `#[serde(with = "crate::utils::non_finite")]` on `f32` or `Vec<f32>` fields.
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Float {
+    Number(f32),
+    Text(String),
+}
+
+impl From<f32> for Float {
+    fn from(value: f32) -> Self {
+        if value.is_nan() {
+            Float::Text("NaN".to_string())
+        } else if value.is_infinite() {
+            Float::Text(if value > 0.0 { "inf" } else { "-inf" }.to_string())
+        } else {
+            Float::Number(value)
+        }
+    }
+}
+
+impl Float {
+    fn into_f32<E: Error>(self) -> Result<f32, E> {
+        match self {
+            Float::Number(x) => Ok(x),
+            Float::Text(text) => match text.as_str() {
+                "NaN" => Ok(f32::NAN),
+                "inf" => Ok(f32::INFINITY),
+                "-inf" => Ok(f32::NEG_INFINITY),
+                _ => Err(E::custom(format!("Invalid float {text:?}"))),
+            },
+        }
+    }
+}
+
+/// Field types which can hold non-finite floats.
+pub trait NonFinite: Sized {
+    fn serialize_non_finite<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+    fn deserialize_non_finite<'de, D: Deserializer<'de>>(deserializer: D)
+        -> Result<Self, D::Error>;
+}
+
+impl NonFinite for f32 {
+    fn serialize_non_finite<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Float::from(*self).serialize(serializer)
+    }
+
+    fn deserialize_non_finite<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Float::deserialize(deserializer)?.into_f32()
+    }
+}
+
+impl NonFinite for Vec<f32> {
+    fn serialize_non_finite<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|x| Float::from(*x)))
+    }
+
+    fn deserialize_non_finite<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Vec::<Float>::deserialize(deserializer)?
+            .into_iter()
+            .map(Float::into_f32)
+            .collect()
+    }
+}
+
+pub fn serialize<T: NonFinite, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    value.serialize_non_finite(serializer)
+}
+
+pub fn deserialize<'de, T: NonFinite, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    T::deserialize_non_finite(deserializer)
+}
@@ -0,0 +1,228 @@
+use crate::error::BackscatterError;
+use chrono::NaiveDate;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Registry bundled with the library, in the layout of RST's radar.dat
+const EMBEDDED_REGISTRY: &str = include_str!("../../data/radar.dat");
+/// Hemispheres and channels of the stations, which radar.dat does not record
+const EMBEDDED_STATION_INFO: &str = include_str!("../../data/station_info.dat");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Hemisphere {
+    North,
+    South,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StationStatus {
+    Operational,
+    /// Testing or under construction
+    Testing,
+    Decommissioned,
+}
+
+impl fmt::Display for StationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StationStatus::Operational => write!(f, "operational"),
+            StationStatus::Testing => write!(f, "testing"),
+            StationStatus::Decommissioned => write!(f, "decommissioned"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Station {
+    pub id: i16,
+    /// Three-letter code, e.g. "sas"
+    pub code: String,
+    /// Every code of the station, starting with `code`, e.g. "sas" and "t"
+    pub codes: Vec<String>,
+    pub name: String,
+    pub operator: String,
+    /// Name of the hdw file of the station, e.g. "hdw.dat.sas"
+    pub hdw_file: String,
+    pub status: StationStatus,
+    /// Date the radar started operating, if known
+    pub start: Option<NaiveDate>,
+    /// Date the radar stopped operating, if it has
+    pub end: Option<NaiveDate>,
+    /// Hemisphere of the radar, if the station info lists it
+    pub hemisphere: Option<Hemisphere>,
+    /// Channels the radar records: 0 for a single channel, 1 and 2 for stereo channels A and B.
+    /// Empty if the station info does not list the station.
+    pub channels: Vec<i16>,
+}
+
+/// Lookup table of the SuperDARN stations, by id, code or name.
+#[derive(Debug, Clone, Default)]
+pub struct StationRegistry {
+    stations: Vec<Station>,
+}
+
+impl StationRegistry {
+    /// The registry bundled with the library.
+    pub fn embedded() -> &'static StationRegistry {
+        static REGISTRY: OnceLock<StationRegistry> = OnceLock::new();
+        REGISTRY.get_or_init(|| {
+            let mut registry = StationRegistry::parse(EMBEDDED_REGISTRY.as_bytes())
+                .expect("Embedded station registry is invalid");
+            registry
+                .add_station_info(EMBEDDED_STATION_INFO.as_bytes())
+                .expect("Embedded station info is invalid");
+            registry
+        })
+    }
+
+    /// Reads a radar.dat file, taking the hemispheres and channels of its stations from the
+    /// station info bundled with the library.
+    pub fn from_file(path: &Path) -> Result<StationRegistry, BackscatterError> {
+        let file = File::open(path).map_err(|e| {
+            BackscatterError::new(&format!("Unable to open {}: {}", path.display(), e))
+        })?;
+        let mut registry = StationRegistry::parse(BufReader::new(file))?;
+        registry.add_station_info(EMBEDDED_STATION_INFO.as_bytes())?;
+        Ok(registry)
+    }
+
+    /// Parses a registry in the layout of RST's radar.dat, with one station per line: id,
+    /// status, start and end dates as YYYYMMDD, then quoted name, operator, hdw file and one or
+    /// more codes. Dates of 19000101 are not set. Lines starting with `#` are comments.
+    pub fn parse(reader: impl BufRead) -> Result<StationRegistry, BackscatterError> {
+        let mut stations = vec![];
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = line
+                .map_err(|_| BackscatterError::new("Unable to read line from station registry"))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |field: &str| {
+                BackscatterError::new(&format!(
+                    "Unable to read {} from line {} of station registry",
+                    field,
+                    line_num + 1
+                ))
+            };
+            let (fields, quoted) = line.split_once('"').ok_or_else(|| err("name"))?;
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            if fields.len() != 4 {
+                Err(err("columns"))?
+            }
+            // Quoted strings alternate with the whitespace between them
+            let parts: Vec<&str> = quoted.split('"').collect();
+            if parts
+                .chunks(2)
+                .any(|pair| pair.len() != 2 || !pair[1].trim().is_empty())
+            {
+                Err(err("quoted columns"))?
+            }
+            let quoted: Vec<&str> = parts.chunks(2).map(|pair| pair[0]).collect();
+            if quoted.len() < 4 {
+                Err(err("codes"))?
+            }
+            let date = |field: &str, value: &str| match value {
+                "19000101" => Ok(None),
+                _ => NaiveDate::parse_from_str(value, "%Y%m%d")
+                    .map(Some)
+                    .map_err(|_| err(field)),
+            };
+            let codes: Vec<String> = quoted[3..].iter().map(|c| c.to_string()).collect();
+            if quoted[2].is_empty() {
+                Err(err("hdw file"))?
+            }
+            stations.push(Station {
+                id: fields[0].parse().map_err(|_| err("id"))?,
+                status: match fields[1] {
+                    "1" => StationStatus::Operational,
+                    "0" => StationStatus::Testing,
+                    "-1" => StationStatus::Decommissioned,
+                    _ => Err(err("status"))?,
+                },
+                start: date("start date", fields[2])?,
+                end: date("end date", fields[3])?,
+                name: quoted[0].to_string(),
+                operator: quoted[1].to_string(),
+                hdw_file: quoted[2].to_string(),
+                code: codes[0].clone(),
+                codes,
+                hemisphere: None,
+                channels: vec![],
+            });
+        }
+        Ok(StationRegistry { stations })
+    }
+
+    /// Sets the hemispheres and channels of the stations from a station info file, with one
+    /// station per line: id, hemisphere as N or S, and comma-separated channels. Text after a
+    /// `#` is a comment. Stations which are not in the registry are ignored.
+    pub fn add_station_info(&mut self, reader: impl BufRead) -> Result<(), BackscatterError> {
+        for (line_num, line) in reader.lines().enumerate() {
+            let line =
+                line.map_err(|_| BackscatterError::new("Unable to read line from station info"))?;
+            let fields: Vec<&str> = match line.split_once('#') {
+                Some((data, _)) => data,
+                None => &line,
+            }
+            .split_whitespace()
+            .collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let err = |field: &str| {
+                BackscatterError::new(&format!(
+                    "Unable to read {} from line {} of station info",
+                    field,
+                    line_num + 1
+                ))
+            };
+            if fields.len() != 3 {
+                Err(err("columns"))?
+            }
+            let id: i16 = fields[0].parse().map_err(|_| err("id"))?;
+            let hemisphere = match fields[1] {
+                "N" => Hemisphere::North,
+                "S" => Hemisphere::South,
+                _ => Err(err("hemisphere"))?,
+            };
+            let channels = fields[2]
+                .split(',')
+                .map(|c| c.parse().map_err(|_| err("channels")))
+                .collect::<Result<Vec<i16>, _>>()?;
+            if let Some(station) = self.stations.iter_mut().find(|s| s.id == id) {
+                station.hemisphere = Some(hemisphere);
+                station.channels = channels;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn by_id(&self, id: i16) -> Option<&Station> {
+        self.stations.iter().find(|s| s.id == id)
+    }
+
+    /// Finds a station by any of its codes, ignoring case.
+    pub fn by_code(&self, code: &str) -> Option<&Station> {
+        self.stations
+            .iter()
+            .find(|s| s.codes.iter().any(|c| c.eq_ignore_ascii_case(code)))
+    }
+
+    /// Finds a station by its name, ignoring case.
+    pub fn by_name(&self, name: &str) -> Option<&Station> {
+        self.stations
+            .iter()
+            .find(|s| s.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn stations(&self) -> &[Station] {
+        &self.stations
+    }
+}
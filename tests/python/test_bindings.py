@@ -0,0 +1,44 @@
+"""Tests of the Python bindings.
+
+Build and install the module with `maturin develop --extras test`, then run `pytest tests/python`.
+"""
+from pathlib import Path
+
+import numpy as np
+import backscatter_rs
+
+TEST_FILES = Path(__file__).parent.parent / "test_files"
+
+
+def test_fit_rawacf_record():
+    rawacf = backscatter_rs.read_rawacf(TEST_FILES / "test.rawacf")
+    fitacf = backscatter_rs.read_fitacf(TEST_FILES / "test.fitacf")
+    assert len(rawacf) > 0
+
+    hdw = backscatter_rs.HdwInfo(rawacf[0].station_id, rawacf[0].datetime)
+    assert hdw.station_id == rawacf[0].station_id
+
+    for rec, expected in zip(rawacf, fitacf):
+        fitted = backscatter_rs.fit_rawacf_record(rec, hdw)
+        assert fitted == expected
+        assert isinstance(fitted.velocity, np.ndarray)
+        assert fitted.velocity.dtype == np.float32
+        assert fitted.velocity.shape == fitted.range_list.shape
+        np.testing.assert_array_equal(fitted.velocity, expected.velocity)
+        np.testing.assert_array_equal(fitted.lambda_power, expected.lambda_power)
+
+
+def test_acf_shape():
+    rec = backscatter_rs.read_rawacf(TEST_FILES / "test.rawacf")[0]
+    assert rec.num_lags > 0
+    assert rec.acfs.shape == (len(rec.range_list), rec.num_lags, 2)
+
+
+def test_write_fitacf(tmp_path):
+    rawacf = backscatter_rs.read_rawacf(TEST_FILES / "test.rawacf")
+    hdw = backscatter_rs.HdwInfo(rawacf[0].station_id, rawacf[0].datetime)
+    fitted = [backscatter_rs.fit_rawacf_record(rec, hdw) for rec in rawacf]
+
+    outfile = tmp_path / "out.fitacf"
+    backscatter_rs.write_fitacf(outfile, fitted)
+    assert backscatter_rs.read_fitacf(outfile) == fitted
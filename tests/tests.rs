@@ -1,9 +1,37 @@
-use backscatter_rs::fitting::fitacf3::fitacf_v3::fit_rawacf_record;
-use backscatter_rs::utils::hdw::HdwInfo;
-use chrono::NaiveDateTime;
+use backscatter_rs::fitting::acf_data::AcfRecord;
+use backscatter_rs::fitting::fitacf3::determinations::to_fitacf_record;
+use backscatter_rs::fitting::fitacf3::filtering::filter_outlier_lags;
+use backscatter_rs::fitting::fitacf3::fitacf_v3::{
+    fit_acf_data, fit_ranges, fit_rawacf_record, fit_rawacf_record_with_options, trace_fit_stages,
+    FitOptions, Fitacf3Error,
+};
+use backscatter_rs::fitting::fitacf3::fitstruct::{
+    FitType, FittedData, LagNode, LagRejection, RangeNode,
+};
+use backscatter_rs::fitting::fitacf3::fitting::acf_power_fitting;
+use backscatter_rs::fitting::fitacf3::model::{model_acfs, ModelAcf};
+use backscatter_rs::fitting::fitacf3::stream::fit_stream;
+use backscatter_rs::fitting::validation::{
+    non_finite_errors, rawacf_validation_errors, validate, ValidationError,
+};
+use backscatter_rs::utils::compression::{decompress, CompressedWriter, Compression};
+use backscatter_rs::utils::dmap_stream::{RecordReader, RecordWriter};
+use backscatter_rs::utils::hdw::{HdwInfo, HdwProvider, HdwSource};
+use backscatter_rs::utils::hdw_format::{lint, parse_lines, HdwFormat, Severity};
+use backscatter_rs::utils::hdw_history::HdwHistory;
+use backscatter_rs::utils::stations::{Hemisphere, StationRegistry, StationStatus};
+use chrono::{NaiveDate, NaiveDateTime};
 use dmap::formats::{DmapRecord, FitacfRecord, RawacfRecord};
+use proptest::collection::{btree_set, vec};
+use proptest::option;
+use proptest::prelude::*;
 use std::fs::{remove_file, File};
+use std::io::{Read, Write};
 use std::iter::zip;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
 
 #[test]
 fn test_fitacf3() {
@@ -38,3 +66,1299 @@ fn test_fitacf3() {
     }
     remove_file("tests/test_files/temp.fitacf").expect("Unable to delete file");
 }
+
+#[test]
+fn test_model_acf() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+
+    for rec in rawacf.iter() {
+        let (ranges, _) = fit_ranges(rec, &FitOptions::default()).expect("Could not fit record");
+        for fit_type in [FitType::Linear, FitType::Quadratic] {
+            let acfs =
+                model_acfs(rec, fit_type, &FitOptions::default()).expect("Could not model record");
+            assert_eq!(acfs.len(), ranges.len());
+            for (acf, range) in zip(acfs, ranges.iter()) {
+                assert_eq!(acf.range_idx, range.range_idx);
+                assert_eq!(acf.measured.len(), rec.num_lags as usize);
+                assert_eq!(acf.model.len(), rec.num_lags as usize);
+                for (res, (meas, model)) in zip(acf.residuals.iter(), zip(acf.measured, acf.model))
+                {
+                    assert_eq!(res[0], meas[0] - model[0]);
+                    assert_eq!(res[1], meas[1] - model[1]);
+                }
+            }
+        }
+    }
+
+    // Power of 100 decaying by e^-0.3 over the first lag of 1.5 ms in the linear model, and by
+    // e^-0.3 over the square of that lag in the quadratic model, with the phase turning by pi/2
+    // each lag
+    let rec = AcfRecord {
+        multi_pulse_increment: 1500,
+        num_lags: 3,
+        lag_table: vec![0, 0, 0, 1, 0, 2],
+        acfs: vec![100.0, 0.0, 70.0, 10.0, -50.0, 5.0],
+        ..Default::default()
+    };
+    let lags: Vec<LagNode> = (0..3)
+        .map(|lag_num| LagNode {
+            lag_num,
+            pulses: [0, lag_num as usize],
+            lag_idx: 0,
+            sample_base_1: 0,
+            sample_base_2: 0,
+        })
+        .collect();
+    let range = RangeNode {
+        lin_pwr_fit: Some(FittedData {
+            intercept: 100.0_f64.ln(),
+            slope: -200.0,
+            ..Default::default()
+        }),
+        quad_pwr_fit: Some(FittedData {
+            intercept: 100.0_f64.ln(),
+            slope: -0.3 / 1.5e-3_f64.powi(2),
+            ..Default::default()
+        }),
+        phase_fit: Some(FittedData {
+            slope: std::f64::consts::PI / 2.0 / 1.5e-3,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let acf = ModelAcf::new(&range, &rec, &lags, FitType::Linear).expect("Could not model ACF");
+    assert_close(acf.t[2], 3.0e-3);
+    assert_close(acf.model[0][0], 100.0);
+    assert_close(acf.model[0][1], 0.0);
+    assert_close(acf.model[1][0], 0.0);
+    assert_close(acf.model[1][1], 74.08182206817179);
+    assert_close(acf.model[2][0], -54.88116360940264);
+    assert_close(acf.model[2][1], 0.0);
+    assert_close(acf.residuals[1][0], 70.0);
+    assert_close(acf.residuals[2][0], 4.88116360940264);
+
+    let acf = ModelAcf::new(&range, &rec, &lags, FitType::Quadratic).expect("Could not model ACF");
+    assert_close(acf.model[0][0], 100.0);
+    assert_close(acf.model[1][1], 74.08182206817179);
+    assert_close(acf.model[2][0], -30.119421191220212);
+    assert_close(acf.model[2][1], 0.0);
+    assert_close(acf.residuals[2][0], -19.880578808779788);
+}
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() <= 1e-9 * expected.abs().max(1.0),
+        "{} is not close to {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn test_lag_masks() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+
+    for rec in rawacf.iter() {
+        let (ranges, _) = fit_ranges(rec, &FitOptions::default()).expect("Could not fit record");
+        for range in ranges {
+            let kept_powers = range.power_mask.iter().filter(|m| m.is_none()).count();
+            let kept_phases = range.phase_mask.iter().filter(|m| m.is_none()).count();
+            assert_eq!(kept_powers, range.powers.ln_power.len());
+            assert_eq!(kept_phases, range.phases.phases.len());
+        }
+    }
+}
+
+/// A range whose log power decays linearly in time, with a sigma of 0.1 in log power
+fn decaying_power_range(intercept: f64, slope: f64, num_lags: usize) -> RangeNode {
+    let t: Vec<f64> = (0..num_lags).map(|i| i as f64).collect();
+    let mut range = RangeNode::default();
+    range.powers.ln_power = t.iter().map(|t| intercept + slope * t).collect();
+    range.powers.std_dev = range
+        .powers
+        .ln_power
+        .iter()
+        .map(|p| 0.1 * p.exp())
+        .collect();
+    range.powers.t = t;
+    range.powers.lag_idx = (0..num_lags).collect();
+    range.power_alpha_2 = vec![1.0; num_lags];
+    range.power_mask = vec![None; num_lags];
+    range.phase_mask = vec![None; num_lags];
+    range
+}
+
+#[test]
+fn test_outlier_clipping() {
+    let (intercept, slope) = (5.0, -0.05);
+    let mut range = decaying_power_range(intercept, slope, 10);
+    range.powers.ln_power[4] += 1.0;
+    let mut ranges = vec![range];
+
+    acf_power_fitting(&mut ranges, false).expect("Could not fit power");
+    let contaminated_slope = ranges[0].lin_pwr_fit.as_ref().expect("No power fit").slope;
+    assert_eq!(filter_outlier_lags(&mut ranges, 3.0), 1);
+    assert_eq!(ranges[0].num_clipped_lags, 1);
+    for (lag, mask) in ranges[0].power_mask.iter().enumerate() {
+        let expected = if lag == 4 {
+            Some(LagRejection::Clipped)
+        } else {
+            None
+        };
+        assert_eq!(*mask, expected);
+    }
+
+    acf_power_fitting(&mut ranges, false).expect("Could not fit power");
+    let refit_slope = ranges[0].lin_pwr_fit.as_ref().expect("No power fit").slope;
+    assert!((refit_slope - slope).abs() < (contaminated_slope - slope).abs());
+    assert!((refit_slope - slope).abs() < 1e-9);
+    assert_eq!(filter_outlier_lags(&mut ranges, 3.0), 0);
+}
+
+#[test]
+fn test_outlier_clipping_linear_only() {
+    // Power is only clipped against the linear fit, so a steep exponential decay keeps the lags
+    // which the quadratic model misses by more than the limit
+    let mut ranges = vec![decaying_power_range(5.0, -0.5, 10)];
+    acf_power_fitting(&mut ranges, false).expect("Could not fit power");
+    let powers = &ranges[0].powers;
+    let quad = ranges[0].quad_pwr_fit.as_ref().expect("No power fit");
+    let quadratic_outliers = (0..powers.t.len())
+        .filter(|&i| {
+            let sigma = powers.std_dev[i] / powers.ln_power[i].exp();
+            let model = quad.intercept + quad.slope * powers.t[i] * powers.t[i];
+            ((powers.ln_power[i] - model) / sigma).abs() > 3.0
+        })
+        .count();
+    assert!(quadratic_outliers > 0);
+    assert_eq!(filter_outlier_lags(&mut ranges, 3.0), 0);
+    assert_eq!(ranges[0].num_clipped_lags, 0);
+    assert!(ranges[0].power_mask.iter().all(|m| m.is_none()));
+}
+
+#[test]
+fn test_parallel_ranges() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let hdw = HdwInfo::new(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file");
+    let parallel = FitOptions {
+        parallel_ranges: true,
+        ..Default::default()
+    };
+
+    for rec in rawacf.iter() {
+        let sequential_rec = fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+        let parallel_rec =
+            fit_rawacf_record_with_options(rec, &hdw, &parallel).expect("Could not fit record");
+        assert_eq!(sequential_rec, parallel_rec);
+    }
+}
+
+#[test]
+fn test_fit_stream() {
+    let bytes = std::fs::read("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(&bytes[..]).expect("Could not read records");
+    let streamed: Vec<RawacfRecord> = RecordReader::new(&bytes[..])
+        .collect::<Result<_, _>>()
+        .expect("Could not stream records");
+    assert_eq!(streamed, rawacf);
+
+    let mut provider = HdwProvider::new(HdwSource::Default);
+    let hdw = provider
+        .get(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file")
+        .clone();
+    let expected: Vec<FitacfRecord> = rawacf
+        .iter()
+        .map(|rec| fit_rawacf_record(rec, &hdw).expect("Could not fit record"))
+        .collect();
+    for parallel_ranges in [false, true] {
+        let options = FitOptions {
+            parallel_ranges,
+            ..Default::default()
+        };
+        let mut output = vec![];
+        let summary = fit_stream(&bytes[..], &mut output, &mut provider, &options, 3, false)
+            .expect("Could not fit stream");
+        assert_eq!(summary.written, rawacf.len());
+        assert!(summary.skipped.is_empty());
+        let fitacf = FitacfRecord::read_records(&output[..]).expect("Could not read records");
+        assert_eq!(fitacf, expected);
+    }
+
+    // A record which cannot be fitted in the middle of a window is skipped, unless strict
+    let mut with_bad_record = vec![];
+    let mut writer = RecordWriter::new(&mut with_bad_record);
+    for (i, rec) in rawacf.iter().enumerate() {
+        let mut rec = rec.clone();
+        if i == 1 {
+            rec.sample_separation = 0;
+        }
+        writer.write(&rec).expect("Could not write record");
+    }
+    let mut output = vec![];
+    let summary = fit_stream(
+        &with_bad_record[..],
+        &mut output,
+        &mut provider,
+        &FitOptions::default(),
+        3,
+        false,
+    )
+    .expect("Could not fit stream");
+    assert_eq!(summary.written, rawacf.len() - 1);
+    assert_eq!(summary.skipped, vec![1]);
+    let fitacf = FitacfRecord::read_records(&output[..]).expect("Could not read records");
+    let mut without_bad_record = expected.clone();
+    without_bad_record.remove(1);
+    assert_eq!(fitacf, without_bad_record);
+    assert!(fit_stream(
+        &with_bad_record[..],
+        &mut vec![],
+        &mut provider,
+        &FitOptions::default(),
+        3,
+        true,
+    )
+    .is_err());
+
+    // A truncated stream fails on the last record rather than ending early
+    let truncated: Vec<Result<RawacfRecord, _>> =
+        RecordReader::new(&bytes[..bytes.len() - 1]).collect();
+    assert_eq!(truncated.len(), rawacf.len());
+    assert!(truncated.last().is_some_and(|rec| rec.is_err()));
+
+    // The records read in the same window before the unreadable one are still written
+    let mut output = vec![];
+    let result = fit_stream(
+        &bytes[..bytes.len() - 1],
+        &mut output,
+        &mut provider,
+        &FitOptions::default(),
+        rawacf.len() + 1,
+        false,
+    );
+    assert!(result.is_err());
+    let fitacf = FitacfRecord::read_records(&output[..]).expect("Could not read records");
+    assert_eq!(fitacf, expected[..rawacf.len() - 1]);
+}
+
+#[test]
+fn test_compression() {
+    let data: Vec<u8> = (0..10000u32)
+        .flat_map(|x| (x % 251).to_le_bytes())
+        .collect();
+    for compression in [
+        Compression::None,
+        Compression::Bzip2,
+        Compression::Gzip,
+        Compression::Zstd,
+    ] {
+        let mut writer =
+            CompressedWriter::new(vec![], compression).expect("Could not create writer");
+        writer.write_all(&data).expect("Could not compress data");
+        let compressed = writer.finish().expect("Could not finish compression");
+        assert_eq!(Compression::from_magic(&compressed), compression);
+
+        let mut decompressed = vec![];
+        decompress(&compressed[..])
+            .and_then(|mut reader| reader.read_to_end(&mut decompressed))
+            .expect("Could not decompress data");
+        assert_eq!(decompressed, data);
+
+        let name = match compression.extension() {
+            Some(extension) => format!("test.rawacf.{}", extension),
+            None => "test.rawacf".to_string(),
+        };
+        assert_eq!(Compression::from_extension(Path::new(&name)), compression);
+        assert_eq!(compression.to_string().parse().ok(), Some(compression));
+    }
+}
+
+/// Runs the procdarn binary, returning whether it succeeded and what it logged
+fn procdarn(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_procdarn"))
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .expect("Unable to run procdarn");
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    )
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("Path is not valid UTF-8")
+}
+
+#[test]
+fn test_batch_outputs() {
+    let dir = std::env::temp_dir().join(format!("backscatter_batch_{}", std::process::id()));
+    let inputs = dir.join("in");
+    for sub in ["a", "b"] {
+        std::fs::create_dir_all(inputs.join(sub)).expect("Unable to create input dir");
+        std::fs::copy(
+            "tests/test_files/test.rawacf",
+            inputs.join(sub).join("20210607.1801.00.cly.a.rawacf"),
+        )
+        .expect("Unable to copy test file");
+    }
+    let outputs = dir.join("out");
+
+    // Outputs keep the subdirectory of their input, and take the extension of the compression
+    let (success, log) = procdarn(&[
+        "fit",
+        "-i",
+        path_str(&inputs),
+        "--outdir",
+        path_str(&outputs),
+        "--compress",
+        "gz",
+    ]);
+    assert!(success, "{}", log);
+    for sub in ["a", "b"] {
+        let output = outputs.join(sub).join("20210607.1801.00.cly.a.fitacf.gz");
+        let compressed = std::fs::read(&output).expect("Output not found");
+        let decompressed = decompress(&compressed[..]).expect("Could not decompress output");
+        let fitacf = FitacfRecord::read_records(decompressed).expect("Could not read records");
+        assert!(!fitacf.is_empty());
+    }
+
+    // Flattening would write both inputs to the same output
+    let (success, log) = procdarn(&[
+        "fit",
+        "-i",
+        path_str(&inputs),
+        "--outdir",
+        path_str(&dir.join("flat")),
+        "--flatten",
+    ]);
+    assert!(!success);
+    assert!(
+        log.contains("More than one input would be written"),
+        "{}",
+        log
+    );
+    assert!(!dir.join("flat").exists());
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete batch dir");
+}
+
+#[test]
+fn test_batch_resume() {
+    let dir = std::env::temp_dir().join(format!("backscatter_resume_{}", std::process::id()));
+    let inputs = dir.join("in");
+    std::fs::create_dir_all(&inputs).expect("Unable to create input dir");
+    let input = inputs.join("test.rawacf");
+    let bytes = std::fs::read("tests/test_files/test.rawacf").expect("Test file not found");
+    std::fs::write(&input, &bytes).expect("Unable to write input");
+    let fit = |outputs: &Path, resume: &str| {
+        procdarn(&[
+            "--log-level",
+            "info",
+            "fit",
+            "-i",
+            path_str(&inputs),
+            "--outdir",
+            path_str(outputs),
+            "--resume",
+            resume,
+        ])
+    };
+
+    // An output newer than its input is complete, whatever it holds
+    let outputs = dir.join("timestamp");
+    std::fs::create_dir_all(&outputs).expect("Unable to create output dir");
+    let output = outputs.join("test.fitacf");
+    std::fs::write(&output, "complete").expect("Unable to write output");
+    let (success, log) = fit(&outputs, "timestamp");
+    assert!(success, "{}", log);
+    assert!(log.contains("Skipping input"), "{}", log);
+    assert_eq!(std::fs::read(&output).ok(), Some(b"complete".to_vec()));
+    File::options()
+        .write(true)
+        .open(&input)
+        .and_then(|f| f.set_modified(SystemTime::now() + Duration::from_secs(60)))
+        .expect("Unable to touch input");
+    let (success, log) = fit(&outputs, "timestamp");
+    assert!(success, "{}", log);
+    assert!(!log.contains("Skipping input"), "{}", log);
+    assert_ne!(std::fs::read(&output).ok(), Some(b"complete".to_vec()));
+
+    // An output is complete while its input has the checksum recorded when it was written
+    let outputs = dir.join("checksum");
+    let (success, log) = fit(&outputs, "checksum");
+    assert!(success, "{}", log);
+    assert!(!log.contains("Skipping input"), "{}", log);
+    std::fs::write(&input, &bytes).expect("Unable to write input");
+    let (success, log) = fit(&outputs, "checksum");
+    assert!(success, "{}", log);
+    assert!(log.contains("Skipping input"), "{}", log);
+
+    let rawacf = RawacfRecord::read_records(&bytes[..]).expect("Could not read records");
+    let mut writer = RecordWriter::new(File::create(&input).expect("Unable to write input"));
+    writer.write(&rawacf[0]).expect("Could not write record");
+    drop(writer);
+    let (success, log) = fit(&outputs, "checksum");
+    assert!(success, "{}", log);
+    assert!(!log.contains("Skipping input"), "{}", log);
+    let fitacf = FitacfRecord::read_records(
+        File::open(outputs.join("test.fitacf")).expect("Output not found"),
+    )
+    .expect("Could not read records");
+    assert_eq!(fitacf.len(), 1);
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete resume dir");
+}
+
+#[test]
+fn test_fit_stdio() {
+    let bytes = std::fs::read("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(&bytes[..]).expect("Could not read records");
+    let hdw = HdwInfo::new(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file");
+    let expected: Vec<FitacfRecord> = rawacf
+        .iter()
+        .map(|rec| fit_rawacf_record(rec, &hdw).expect("Could not fit record"))
+        .collect();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_procdarn"))
+        .args(["fit", "-i", "-", "-o", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Unable to run procdarn");
+    let mut stdin = child.stdin.take().expect("No stdin");
+    let stdout = child.stdout.take().expect("No stdout");
+    let (sender, receiver) = mpsc::channel();
+    let reader = std::thread::spawn(move || {
+        for rec in RecordReader::<FitacfRecord, _>::new(stdout) {
+            if sender.send(rec.expect("Could not read record")).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Each record is passed on before the next is written
+    for (rec, expected) in zip(rawacf.iter(), expected.iter()) {
+        stdin
+            .write_all(&rec.to_bytes())
+            .and_then(|_| stdin.flush())
+            .expect("Could not write record");
+        let fitted = receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("Record was not passed on");
+        assert_eq!(fitted, *expected);
+    }
+    drop(stdin);
+    assert!(receiver.recv().is_err());
+    reader.join().expect("Unable to read records");
+    assert!(child.wait().expect("procdarn did not run").success());
+}
+
+#[test]
+fn test_validate_command() {
+    let dir = std::env::temp_dir().join(format!("backscatter_validate_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Unable to create validate dir");
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let write = |name: &str, bad_record: bool| {
+        let path = dir.join(name);
+        let mut writer = RecordWriter::new(File::create(&path).expect("Unable to write input"));
+        for (i, rec) in rawacf.iter().enumerate() {
+            let mut rec = rec.clone();
+            if i == 0 {
+                rec.acfs.data[1] = f32::NAN;
+            }
+            if i == 1 && bad_record {
+                rec.sample_separation = 0;
+            }
+            writer.write(&rec).expect("Could not write record");
+        }
+        path
+    };
+    let validate = |path: &Path| {
+        let output = Command::new(env!("CARGO_BIN_EXE_procdarn"))
+            .args(["validate", "-i", path_str(path)])
+            .output()
+            .expect("Unable to run procdarn");
+        (
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        )
+    };
+
+    // Non-finite samples are only warned about, as the record can still be fitted
+    let (success, report) = validate(&write("nan.rawacf", false));
+    assert!(success, "{}", report);
+    assert!(
+        report.contains("record 0: warning: acfs[1] is not finite"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains(&format!(
+            "0 of {} records invalid, 1 with non-finite samples",
+            rawacf.len()
+        )),
+        "{}",
+        report
+    );
+
+    let (success, report) = validate(&write("invalid.rawacf", true));
+    assert!(!success, "{}", report);
+    assert!(report.contains("record 1: sample_separation"), "{}", report);
+    assert!(report.contains(&format!("1 of {} records invalid", rawacf.len())));
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete validate dir");
+}
+
+#[test]
+fn test_dumps_skip_unfittable_records() {
+    let dir = std::env::temp_dir().join(format!("backscatter_dumps_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Unable to create dump dir");
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let input = dir.join("input.rawacf");
+    let mut writer = RecordWriter::new(File::create(&input).expect("Unable to write input"));
+    for (i, rec) in rawacf.iter().enumerate() {
+        let mut rec = rec.clone();
+        if i == 1 {
+            rec.sample_separation = 0;
+        }
+        writer.write(&rec).expect("Could not write record");
+    }
+    drop(writer);
+
+    let (acf_dump, mask_dump) = (dir.join("acfs.txt"), dir.join("masks.txt"));
+    let (success, log) = procdarn(&[
+        "fit",
+        "-i",
+        path_str(&input),
+        "-o",
+        path_str(&dir.join("output.fitacf")),
+        "--acf-dump",
+        path_str(&acf_dump),
+        "--lag-mask-dump",
+        path_str(&mask_dump),
+    ]);
+    assert!(success, "{}", log);
+    assert!(log.contains("Skipping record"), "{}", log);
+    for dump in [&acf_dump, &mask_dump] {
+        let records: Vec<usize> = std::fs::read_to_string(dump)
+            .expect("Dump not found")
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split(' ').next().and_then(|r| r.parse().ok()))
+            .collect::<Option<_>>()
+            .expect("Invalid dump line");
+        assert!(records.contains(&0));
+        assert!(records.contains(&2));
+        assert!(!records.contains(&1));
+    }
+
+    // With --strict the record fails the dump, as it fails the fit
+    let (success, _) = procdarn(&[
+        "fit",
+        "-i",
+        path_str(&input),
+        "-o",
+        path_str(&dir.join("strict.fitacf")),
+        "--acf-dump",
+        path_str(&acf_dump),
+        "--strict",
+    ]);
+    assert!(!success);
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete dump dir");
+}
+
+fn record_datetime(rec: &RawacfRecord) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(
+        format!(
+            "{:4}{:0>2}{:0>2} {:0>2}:{:0>2}:{:0>2}",
+            rec.year, rec.month, rec.day, rec.hour, rec.minute, rec.second
+        )
+        .as_str(),
+        "%Y%m%d %H:%M:%S",
+    )
+    .expect("Unable to interpret record timestamp")
+}
+
+#[test]
+fn test_fit_acf_record() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let options = FitOptions::default();
+    let hdw = HdwInfo::new(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file");
+
+    for rec in rawacf.iter() {
+        let acf_record = AcfRecord::from(rec);
+        assert_eq!(acf_record.num_lags, rec.num_lags);
+        assert_eq!(validate(&acf_record), Ok(()));
+        assert_eq!(
+            fit_acf_data(&acf_record, &hdw, &options).expect("Could not fit record"),
+            fit_acf_data(rec, &hdw, &options).expect("Could not fit record")
+        );
+
+        let (rawacf_ranges, _) = fit_ranges(rec, &options).expect("Could not fit record");
+        let (acf_ranges, _) = fit_ranges(&acf_record, &options).expect("Could not fit record");
+        assert_eq!(rawacf_ranges.len(), acf_ranges.len());
+        for (a, b) in zip(rawacf_ranges.iter(), acf_ranges.iter()) {
+            assert_eq!(a.range_num, b.range_num);
+            assert_eq!(
+                a.phase_fit.as_ref().map(|f| f.slope),
+                b.phase_fit.as_ref().map(|f| f.slope)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_validation() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    for rec in rawacf.iter() {
+        assert_eq!(rawacf_validation_errors(rec), vec![]);
+    }
+
+    let valid = AcfRecord::from(&rawacf[0]);
+    assert_eq!(validate(&valid), Ok(()));
+
+    let mut rec = valid.clone();
+    rec.sample_separation = 0;
+    assert!(matches!(
+        validate(&rec),
+        Err(ValidationError::OutOfRange {
+            field: "sample_separation",
+            ..
+        })
+    ));
+    assert!(matches!(
+        fit_ranges(&rec, &FitOptions::default()),
+        Err(Fitacf3Error::Invalid(_))
+    ));
+
+    let mut rec = valid.clone();
+    rec.lag_zero_power.pop();
+    assert!(matches!(
+        validate(&rec),
+        Err(ValidationError::LengthMismatch {
+            field: "lag_zero_power",
+            ..
+        })
+    ));
+
+    let mut rec = valid.clone();
+    rec.acfs.truncate(rec.acfs.len() - 2);
+    assert!(matches!(
+        validate(&rec),
+        Err(ValidationError::LengthMismatch { field: "acfs", .. })
+    ));
+
+    let mut rec = valid.clone();
+    rec.range_list[0] = rec.num_ranges;
+    assert!(matches!(
+        validate(&rec),
+        Err(ValidationError::RangeOutOfBounds { index: 0, .. })
+    ));
+
+    let mut rec = valid.clone();
+    rec.lag_table[1] = i16::MAX;
+    assert!(matches!(
+        validate(&rec),
+        Err(ValidationError::LagNotInPulseTable { lag: 0, .. })
+    ));
+
+    // A non-finite sample is reported, but only rejects the lag holding it when fitting
+    let (ranges, _) = fit_ranges(&valid, &FitOptions::default()).expect("Unable to fit record");
+    let range = &ranges[0];
+    let lag = range
+        .power_mask
+        .iter()
+        .position(|m| m.is_none())
+        .expect("No lags kept");
+    let num_lags = valid.num_lags as usize;
+    let mut rec = valid.clone();
+    rec.acfs[(range.range_idx * num_lags + lag) * 2 + 1] = f32::NAN;
+    assert_eq!(validate(&rec), Ok(()));
+    assert_eq!(
+        non_finite_errors(&rec),
+        vec![ValidationError::NonFinite {
+            field: "acfs",
+            index: (range.range_idx * num_lags + lag) * 2 + 1
+        }]
+    );
+    let (ranges, _) = fit_ranges(&rec, &FitOptions::default()).expect("Unable to fit record");
+    let refit = ranges
+        .iter()
+        .find(|r| r.range_idx == range.range_idx)
+        .expect("Range with a NaN lag was not fitted");
+    assert_eq!(refit.power_mask[lag], Some(LagRejection::NonFinite));
+
+    let mut rec = rawacf[0].clone();
+    rec.month = 13;
+    rec.acfs.dimensions.push(2);
+    let errors = rawacf_validation_errors(&rec);
+    assert!(matches!(errors[0], ValidationError::InvalidTime(_)));
+    assert!(matches!(
+        errors[1],
+        ValidationError::DimensionMismatch { field: "acfs", .. }
+    ));
+}
+
+#[test]
+fn test_fit_result() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let hdw = HdwInfo::new(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file");
+
+    for rec in rawacf.iter() {
+        let result = fit_acf_data(rec, &hdw, &FitOptions::default()).expect("Could not fit record");
+        let fitacf = fit_rawacf_record(rec, &hdw).expect("Could not fit record");
+        assert_eq!(result.ranges.len(), fitacf.range_list.data.len());
+        for (range, velocity) in zip(result.ranges.iter(), fitacf.velocity.data.iter()) {
+            assert_eq!(range.velocity, *velocity);
+        }
+        assert_eq!(to_fitacf_record(rec, &result), fitacf);
+    }
+}
+
+#[test]
+fn test_fit_trace() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let options = FitOptions::default();
+
+    for rec in rawacf.iter() {
+        let trace = trace_fit_stages(rec, &options).expect("Could not fit record");
+        let (ranges, noise_power) = fit_ranges(rec, &options).expect("Could not fit record");
+        assert_eq!(trace.noise_power, noise_power);
+        assert_eq!(trace.lags.len(), rec.num_lags as usize);
+        let last = trace.stages.last().expect("No stages recorded");
+        assert_eq!(last.stage, "xcf_phase_fitting");
+        assert_eq!(last.ranges.len(), ranges.len());
+        for (a, b) in zip(last.ranges.iter(), ranges.iter()) {
+            assert_eq!(a.range_num, b.range_num);
+            assert_eq!(
+                a.elev_fit.as_ref().map(|f| f.slope),
+                b.elev_fit.as_ref().map(|f| f.slope)
+            );
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_fit_trace_json() {
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+
+    let trace = trace_fit_stages(&rawacf[0], &FitOptions::default()).expect("Could not fit record");
+    let json = serde_json::to_string(&trace).expect("Could not serialize trace");
+    let value: serde_json::Value = serde_json::from_str(&json).expect("Invalid JSON");
+    assert_eq!(
+        value["stages"].as_array().map(|s| s.len()),
+        Some(trace.stages.len())
+    );
+    assert_eq!(value["stages"][0]["stage"], "initial");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_fit_result_json_non_finite() {
+    use backscatter_rs::fitting::fitacf3::determinations::{FitResult, RangeFit};
+
+    let range = RangeFit {
+        range_num: 12,
+        fitted_points: 17,
+        clipped_points: 1,
+        quality_flag: 1,
+        ground_flag: 0,
+        lambda_power: 14.5,
+        lambda_power_error: 0.25,
+        sigma_power: 13.75,
+        sigma_power_error: 0.5,
+        velocity: -231.0,
+        velocity_error: 12.5,
+        lambda_spectral_width: 85.0,
+        lambda_spectral_width_error: 6.0,
+        sigma_spectral_width: f32::INFINITY,
+        sigma_spectral_width_error: f32::NEG_INFINITY,
+        lambda_std_dev: 0.125,
+        sigma_std_dev: 0.25,
+        phi_std_dev: 0.5,
+        xcf_phi0: 1.5,
+        xcf_phi0_error: 0.0625,
+        xcf_phi_std_dev: 0.75,
+        elevation: f32::NAN,
+        elevation_low: 10.0,
+        elevation_high: 30.0,
+    };
+    let result = FitResult {
+        sky_noise: 2.5,
+        lag_zero_power_db: vec![3.0, f32::NAN, -1.5],
+        ranges: vec![range],
+    };
+
+    let json = serde_json::to_string(&result).expect("Could not serialize fit result");
+    let value: serde_json::Value = serde_json::from_str(&json).expect("Invalid JSON");
+    assert_eq!(value["ranges"][0]["elevation"], "NaN");
+    assert_eq!(value["ranges"][0]["sigma_spectral_width"], "inf");
+    assert_eq!(value["ranges"][0]["sigma_spectral_width_error"], "-inf");
+    assert_eq!(value["lag_zero_power_db"][1], "NaN");
+    assert_eq!(value["ranges"][0]["velocity"], -231.0);
+
+    let parsed: FitResult = serde_json::from_str(&json).expect("Could not deserialize fit result");
+    let parsed_range = &parsed.ranges[0];
+    assert!(parsed_range.elevation.is_nan());
+    assert!(parsed.lag_zero_power_db[1].is_nan());
+    assert_eq!(parsed_range.sigma_spectral_width, f32::INFINITY);
+    assert_eq!(parsed_range.sigma_spectral_width_error, f32::NEG_INFINITY);
+    assert_eq!(
+        RangeFit {
+            elevation: 0.0,
+            ..parsed_range.clone()
+        },
+        RangeFit {
+            elevation: 0.0,
+            ..result.ranges[0].clone()
+        }
+    );
+    assert_eq!(parsed.sky_noise, result.sky_noise);
+    assert_eq!(parsed.lag_zero_power_db[0], 3.0);
+    assert_eq!(parsed.lag_zero_power_db[2], -1.5);
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn test_capi_fit() {
+    use backscatter_rs::capi::{bs_fit_acf, bs_fit_result_free, BsAcfInput, BsHdw, BsStatus};
+    use std::ptr;
+    use std::slice;
+
+    let file = File::open("tests/test_files/test.rawacf").expect("Test file not found");
+    let rawacf = RawacfRecord::read_records(file).expect("Could not read records");
+    let hdw = HdwInfo::new(rawacf[0].station_id, record_datetime(&rawacf[0]))
+        .expect("Unable to read hdw file");
+    let c_hdw = BsHdw::from(&hdw);
+
+    for rec in rawacf.iter() {
+        let input = BsAcfInput {
+            num_averages: rec.num_averages,
+            num_pulses: rec.num_pulses,
+            num_lags: rec.num_lags,
+            num_ranges: rec.num_ranges,
+            lag_to_first_range: rec.lag_to_first_range,
+            sample_separation: rec.sample_separation,
+            tx_pulse_length: rec.tx_pulse_length,
+            multi_pulse_increment: rec.multi_pulse_increment,
+            offset: rec.offset,
+            channel: rec.channel,
+            beam_num: rec.beam_num,
+            tx_freq: rec.tx_freq,
+            search_noise: rec.search_noise,
+            pulse_table: rec.pulse_table.data.as_ptr(),
+            lag_table: rec.lag_table.data.as_ptr(),
+            lag_zero_power: rec.lag_zero_power.data.as_ptr(),
+            range_list: rec.range_list.data.as_ptr(),
+            range_list_len: rec.range_list.data.len(),
+            acfs: rec.acfs.data.as_ptr(),
+            xcfs: rec.xcfs.as_ref().map_or(ptr::null(), |x| x.data.as_ptr()),
+        };
+        let expected =
+            fit_acf_data(rec, &hdw, &FitOptions::default()).expect("Could not fit record");
+
+        let mut result = ptr::null_mut();
+        let status = unsafe { bs_fit_acf(&input, &c_hdw, &mut result) };
+        assert_eq!(status, BsStatus::Ok);
+        let fitted = unsafe { slice::from_raw_parts((*result).ranges, (*result).ranges_len) };
+        assert_eq!(fitted, expected.ranges.as_slice());
+        unsafe { bs_fit_result_free(result) };
+    }
+
+    let mut result = ptr::null_mut();
+    let status = unsafe { bs_fit_acf(ptr::null(), &c_hdw, &mut result) };
+    assert_eq!(status, BsStatus::NullPointer);
+    assert!(result.is_null());
+}
+
+#[test]
+fn test_hdw_from_dir() {
+    let dir = std::env::temp_dir().join(format!("backscatter_hdw_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Unable to create hdw dir");
+    std::fs::write(
+        dir.join("hdw.dat.cly"),
+        "# Custom hdw file\n\
+         66 1 19930101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.0 0.0 0.0 0.0 0.0 0.0 0.0 0.0 75 16\n\
+         66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n",
+    )
+    .expect("Unable to write hdw file");
+
+    let datetime = NaiveDateTime::parse_from_str("20210607 18:01:00", "%Y%m%d %H:%M:%S")
+        .expect("Invalid datetime");
+    let from_dir = HdwInfo::from_dir(&dir, 66, datetime).expect("Unable to read hdw file");
+    assert_eq!(from_dir.tdiff_a, 0.5);
+    assert_eq!(from_dir.max_num_ranges, 225);
+    let from_file = HdwInfo::from_file(&dir.join("hdw.dat.cly"), 66, datetime)
+        .expect("Unable to read hdw file");
+    assert_eq!(from_file.intf_offset_y, -100.0);
+    assert!(HdwInfo::from_file(&dir.join("hdw.dat.cly"), 65, datetime).is_err());
+    let overridden =
+        HdwInfo::with_override_dir(&dir, 66, datetime).expect("Unable to read hdw file");
+    assert_eq!(overridden.tdiff_a, 0.5);
+    assert!(HdwInfo::from_dir(&dir, 65, datetime).is_err());
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete hdw dir");
+}
+
+#[test]
+fn test_station_registry() {
+    let registry = StationRegistry::embedded();
+    let sas = registry.by_id(5).expect("Saskatoon not found");
+    assert_eq!(sas.code, "sas");
+    assert_eq!(sas.name, "Saskatoon");
+    assert_eq!(registry.by_code("SAS"), Some(sas));
+    assert_eq!(registry.by_name("saskatoon"), Some(sas));
+    assert!(registry.by_id(-1).is_none());
+    assert_eq!(sas.hdw_file, "hdw.dat.sas");
+    assert_eq!(sas.hemisphere, Some(Hemisphere::North));
+    assert_eq!(sas.channels, vec![0]);
+    let hal = registry.by_code("hal").expect("Halley not found");
+    assert_eq!(hal.hemisphere, Some(Hemisphere::South));
+    let han = registry.by_code("han").expect("Hankasalmi not found");
+    assert_eq!(han.channels, vec![0, 1, 2]);
+    assert!(registry
+        .stations()
+        .iter()
+        .all(|s| s.hemisphere.is_some() && !s.channels.is_empty()));
+
+    let mut custom = StationRegistry::parse(
+        "# RST layout\n\
+         300  0 20240601 19000101 \"New Radar\" \"Operator\" \"hdw.dat.xyz\" \"xyz\" \"x\"\n"
+            .as_bytes(),
+    )
+    .expect("Unable to parse registry");
+    assert_eq!(custom.by_id(300).and_then(|s| s.hemisphere), None);
+    custom
+        .add_station_info("300 S 1,2 # New Radar\n# comment\n301 N 0\n".as_bytes())
+        .expect("Unable to parse station info");
+    let xyz = custom.by_id(300).expect("Custom radar not found");
+    assert_eq!(xyz.hemisphere, Some(Hemisphere::South));
+    assert_eq!(xyz.channels, vec![1, 2]);
+    assert_eq!(xyz.status, StationStatus::Testing);
+    assert_eq!(xyz.name, "New Radar");
+    assert_eq!(xyz.operator, "Operator");
+    assert_eq!(xyz.start, NaiveDate::from_ymd_opt(2024, 6, 1));
+    assert_eq!(xyz.end, None);
+    assert_eq!(custom.by_code("x"), Some(xyz));
+    assert_eq!(xyz.hdw_file, "hdw.dat.xyz");
+    assert!(custom.add_station_info("300 east 0\n".as_bytes()).is_err());
+    assert!(custom.add_station_info("300 N a,b\n".as_bytes()).is_err());
+    assert!(StationRegistry::parse("1 1 N 0 gbr \"Goose Bay\"\n".as_bytes()).is_err());
+    assert!(StationRegistry::parse(
+        "1 1 19830101 19000101 \"Goose Bay\" \"\" \"gbr\"\n".as_bytes()
+    )
+    .is_err());
+}
+
+#[test]
+fn test_hdw_provider() {
+    let dir = std::env::temp_dir().join(format!("backscatter_provider_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Unable to create hdw dir");
+    let path = dir.join("hdw.dat.cly");
+    std::fs::write(
+        &path,
+        "66 1 19930101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.0 0.0 0.0 0.0 0.0 0.0 0.0 0.0 75 16\n\
+         66 1 20210607 18:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n",
+    )
+    .expect("Unable to write hdw file");
+
+    let before = NaiveDateTime::parse_from_str("20210607 17:59:59", "%Y%m%d %H:%M:%S")
+        .expect("Invalid datetime");
+    let after = NaiveDateTime::parse_from_str("20210607 18:00:00", "%Y%m%d %H:%M:%S")
+        .expect("Invalid datetime");
+    let mut provider = HdwProvider::new(HdwSource::File(path));
+    assert_eq!(provider.get(66, before).map(|h| h.tdiff_a).ok(), Some(0.0));
+    assert_eq!(
+        provider.lookup(66, after).map(|h| h.tdiff_a).ok(),
+        Some(0.5)
+    );
+    assert!(provider.get(65, after).is_err());
+
+    let mut provider = HdwProvider::new(HdwSource::Dir(dir.clone()));
+    assert_eq!(provider.load_station(66).map(|e| e.len()).ok(), Some(2));
+    assert_eq!(
+        provider.lookup(66, after).map(|h| h.max_num_ranges).ok(),
+        Some(225)
+    );
+
+    std::fs::remove_dir_all(&dir).expect("Unable to delete hdw dir");
+}
+
+#[test]
+fn test_hdw_history() {
+    let entries = HdwInfo::parse_all(
+        "# Two configurations\n\
+         66 1 19930101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.0 0.0 0.0 0.0 0.0 0.0 0.0 0.0 75 16\n\
+         66 1 20120101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.0 0.0 0.0 0.0 0.0 0.0 0.0 0.0 110 16\n\
+         66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n"
+            .as_bytes(),
+    )
+    .expect("Unable to parse hdw file");
+    let history = HdwHistory::from_entries(66, entries);
+    let date = |s: &str| {
+        NaiveDateTime::parse_from_str(&format!("{s} 00:00:00"), "%Y%m%d %H:%M:%S")
+            .expect("Invalid datetime")
+    };
+
+    let periods = history.periods();
+    assert_eq!(periods.len(), 3);
+    assert_eq!(periods[0].end, Some(date("20120101")));
+    assert_eq!(periods[1].start, date("20120101"));
+    assert_eq!(periods[2].end, None);
+    assert_eq!(
+        history.at(date("20150101")).map(|p| p.hdw.max_num_ranges),
+        Some(110)
+    );
+    assert!(history.at(date("19900101")).is_none());
+
+    let changes = history.changes_between(date("20000101"), date("20210101"));
+    let changed: Vec<&str> = changes.iter().map(|c| c.parameter.as_str()).collect();
+    assert_eq!(
+        changed,
+        vec![
+            "max_num_ranges",
+            "tdiff_a",
+            "intf_offset_y",
+            "max_num_ranges"
+        ]
+    );
+    assert_eq!(changes[0].time, date("20120101"));
+    assert_eq!((changes[0].old, changes[0].new), (75.0, 110.0));
+    assert!(history
+        .changes_between(date("20120101"), date("20150101"))
+        .is_empty());
+
+    // Changes can be read back from JSON held at run time
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&changes).expect("Could not serialize changes");
+        let parsed: Vec<backscatter_rs::utils::hdw_history::HdwChange> =
+            serde_json::from_str(&json).expect("Could not deserialize changes");
+        assert_eq!(parsed, changes);
+    }
+}
+
+#[test]
+fn test_hdw_parser() {
+    // Current layout with an extra column, then the legacy RST 4 layout
+    let lines = parse_lines(
+        "66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16 9.9\n\
+         \n\
+         5 1993 86400 52.16 -106.53 494.0 23.1 3.24 1.0 10 0.0 1.0 0.0 -100.0 0.0 100.0 3 75 16\n"
+            .as_bytes(),
+    )
+    .expect("Unable to parse hdw lines");
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].format, HdwFormat::Current);
+    assert_eq!(lines[0].extra_columns, 1);
+    assert_eq!(lines[0].hdw.max_num_beams, 16);
+    assert_eq!(lines[1].line, 3);
+    assert_eq!(lines[1].format, HdwFormat::Legacy);
+    assert_eq!(
+        lines[1].hdw.valid_from,
+        NaiveDateTime::parse_from_str("19930102 00:00:00", "%Y%m%d %H:%M:%S")
+            .expect("Invalid datetime")
+    );
+    assert_eq!(lines[1].hdw.tdiff_b, 0.0);
+    assert_eq!(lines[1].hdw.max_num_ranges, 75);
+
+    let err = parse_lines("66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0\n".as_bytes())
+        .expect_err("Short line was parsed");
+    assert_eq!((err.line, err.column), (1, Some(9)));
+    let err = parse_lines(
+        "# comment\n66 1 20200101 00:00:00 53.35 west 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n"
+            .as_bytes(),
+    )
+    .expect_err("Invalid longitude was parsed");
+    assert_eq!((err.line, err.column), (2, Some(6)));
+
+    let issues = lint(
+        "66 1 20200101 00:00:00 95.0 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n\
+         66 1 20100101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 0\n"
+            .as_bytes(),
+        Some(65),
+    );
+    let errors = issues
+        .iter()
+        .filter(|i| i.severity == Severity::Error)
+        .count();
+    // Both station ids are wrong, and the second entry is out of order
+    assert_eq!(errors, 3);
+    assert!(issues
+        .iter()
+        .any(|i| i.line == 1 && i.message.contains("latitude")));
+    assert!(issues
+        .iter()
+        .any(|i| i.line == 2 && i.message.contains("beams")));
+}
+
+/// Records with arbitrary parameters and arrays of arbitrary lengths, which are mostly invalid
+fn arbitrary_acf_record() -> impl Strategy<Value = AcfRecord> {
+    let parameters = (
+        any::<[i16; 11]>(),
+        any::<f32>(),
+        vec(any::<i16>(), 0..10),
+        vec(any::<i16>(), 0..20),
+    );
+    let data = (
+        vec(any::<f32>(), 0..50),
+        vec(any::<i16>(), 0..20),
+        vec(any::<f32>(), 0..200),
+        option::of(vec(any::<f32>(), 0..200)),
+    );
+    (parameters, data).prop_map(
+        |((p, search_noise, pulse_table, lag_table), (lag_zero_power, range_list, acfs, xcfs))| {
+            AcfRecord {
+                num_averages: p[0],
+                lag_to_first_range: p[1],
+                sample_separation: p[2],
+                tx_pulse_length: p[3],
+                multi_pulse_increment: p[4],
+                offset: p[5],
+                channel: p[6],
+                beam_num: p[7],
+                tx_freq: p[8],
+                num_ranges: p[9],
+                num_lags: p[10],
+                search_noise,
+                pulse_table,
+                lag_table,
+                lag_zero_power,
+                range_list,
+                acfs,
+                xcfs,
+            }
+        },
+    )
+}
+
+/// Records which pass validation, with arbitrary pulse sequences and data, so that every fitting
+/// stage is exercised
+fn valid_acf_record() -> impl Strategy<Value = AcfRecord> {
+    let tables = btree_set(0_i16..64, 1..8).prop_flat_map(|pulses| {
+        let pulses: Vec<i16> = pulses.into_iter().collect();
+        let lag = (0..pulses.len(), 0..pulses.len()).prop_map({
+            let pulses = pulses.clone();
+            move |(a, b)| [pulses[a.min(b)], pulses[a.max(b)]]
+        });
+        (Just(pulses), vec(lag, 0..12))
+    });
+    let ranges = (0_i16..100).prop_flat_map(|num_ranges| {
+        (
+            Just(num_ranges),
+            vec(0.0_f32..1.0e6, num_ranges as usize),
+            btree_set(0..num_ranges.max(1), 0..=num_ranges as usize),
+        )
+    });
+    let parameters = (
+        0_i16..100,
+        0_i16..3000,
+        1_i16..1000,
+        1_i16..1000,
+        1_i16..3000,
+        -1000_i16..1000,
+        0_i16..3,
+        0_i16..24,
+        1_i16..20000,
+        0.0_f32..1.0e4,
+    );
+    (parameters, tables, ranges)
+        .prop_flat_map(
+            |(p, (pulse_table, lags), (num_ranges, powers, range_list))| {
+                let range_list: Vec<i16> =
+                    range_list.into_iter().filter(|&r| r < num_ranges).collect();
+                let len = range_list.len() * lags.len() * 2;
+                (
+                    Just((p, pulse_table, lags, num_ranges, powers, range_list)),
+                    vec(-1.0e6_f32..1.0e6, len),
+                    vec(-1.0e6_f32..1.0e6, len),
+                )
+            },
+        )
+        .prop_map(
+            |((p, pulse_table, lags, num_ranges, lag_zero_power, range_list), acfs, xcfs)| {
+                AcfRecord {
+                    num_averages: p.0,
+                    lag_to_first_range: p.1,
+                    sample_separation: p.2,
+                    tx_pulse_length: p.3,
+                    multi_pulse_increment: p.4,
+                    offset: p.5,
+                    channel: p.6,
+                    beam_num: p.7,
+                    tx_freq: p.8,
+                    search_noise: p.9,
+                    num_ranges,
+                    num_lags: lags.len() as i16,
+                    pulse_table,
+                    lag_table: lags.concat(),
+                    lag_zero_power,
+                    range_list,
+                    acfs,
+                    xcfs: Some(xcfs),
+                }
+            },
+        )
+}
+
+fn test_hdw() -> HdwInfo {
+    parse_lines(
+        "66 1 20200101 00:00:00 53.35 -109.24 100.0 0.0 0.0 3.24 1 1 0.5 0.0 0.0 -100.0 0.0 0.0 0.0 0.0 225 16\n"
+            .as_bytes(),
+    )
+    .expect("Unable to parse hdw line")
+    .remove(0)
+    .hdw
+}
+
+proptest! {
+    #[test]
+    fn prop_fit_arbitrary_record(rec in arbitrary_acf_record()) {
+        let result = fit_acf_data(&rec, &test_hdw(), &FitOptions::default());
+        if validate(&rec).is_err() {
+            prop_assert!(matches!(result, Err(Fitacf3Error::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn prop_fit_valid_record(
+        rec in valid_acf_record(),
+        clip_sigma in option::of(0.5_f64..5.0),
+        parallel_ranges in any::<bool>(),
+    ) {
+        prop_assert_eq!(validate(&rec), Ok(()));
+        let options = FitOptions { clip_sigma, parallel_ranges };
+        if let Ok(result) = fit_acf_data(&rec, &test_hdw(), &options) {
+            prop_assert!(result.ranges.len() <= rec.range_list.len());
+            prop_assert_eq!(result.lag_zero_power_db.len(), rec.lag_zero_power.len());
+        }
+        let _ = model_acfs(&rec, FitType::Quadratic, &options);
+    }
+
+    #[test]
+    fn prop_parse_arbitrary_hdw(text in ".*", line in "[-0-9.: ]{0,120}") {
+        let _ = parse_lines(text.as_bytes());
+        let _ = lint(text.as_bytes(), Some(66));
+        let _ = parse_lines(line.as_bytes());
+        let _ = lint(line.as_bytes(), None);
+    }
+}